@@ -0,0 +1,137 @@
+use crate::{Error, Reason};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+
+///what a user is allowed to do, from most to least
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Admin,
+    ProblemSetter,
+    #[default]
+    Contestant,
+}
+
+impl Role {
+    ///whether a user of this role has all rights of role
+    pub fn has(&self, role: Role) -> bool {
+        *self <= role
+    }
+}
+
+///login and permissions, given in config; with auth disabled everyone may do everything
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Auth {
+    #[serde(default)]
+    pub enabled: bool,
+    ///seconds a login token stays valid
+    #[serde(default = "token_lifetime_default")]
+    pub token_lifetime: i64,
+    ///password given to the root user at start up, the only way to get the first admin
+    pub root_password: Option<String>,
+}
+
+fn token_lifetime_default() -> i64 {
+    7 * 24 * 3600
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Auth {
+            enabled: false,
+            token_lifetime: token_lifetime_default(),
+            root_password: None,
+        }
+    }
+}
+
+///body of POST /login
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Login {
+    pub name: String,
+    pub password: String,
+}
+
+///hash a password with a random salt, in PHC string format
+pub fn hash_password(password: &str) -> Result<String, Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|x| x.to_string())
+        .map_err(|err| Error {
+            reason: Reason::ErrInternal,
+            code: 6,
+            message: format!("failed to hash password: {}", err),
+        })
+}
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+        Err(_) => false,
+    }
+}
+
+///Verify a password of a login against the hash of its user, or against a hash of nobody if there's no user
+/// or the user has no password, so that the time a login takes doesn't tell which user names exist.
+pub fn verify_login(password: &str, hash: Option<&str>) -> bool {
+    static NOBODY: OnceLock<String> = OnceLock::new();
+    match hash {
+        Some(hash) => verify_password(password, hash),
+        None => {
+            verify_password(password, NOBODY.get_or_init(|| hash_password("").unwrap_or_default()));
+            false
+        }
+    }
+}
+
+///a new random login token
+pub fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+///error of a request without a valid token
+pub fn unauthorized() -> Error {
+    Error {
+        reason: Reason::ErrUnauthorized,
+        code: 7,
+        message: "Login required.".to_string(),
+    }
+}
+
+///error of a request by a user without the right to do it
+pub fn forbidden() -> Error {
+    Error {
+        reason: Reason::ErrForbidden,
+        code: 8,
+        message: "Permission denied.".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_is_verified_against_hash() {
+        let hash = hash_password("secret").unwrap();
+        assert!(!hash.contains("secret"));
+        assert!(verify_password("secret", &hash));
+        assert!(!verify_password("guess", &hash));
+        assert_ne!(hash, hash_password("secret").unwrap());
+        assert!(verify_login("secret", Some(&hash)));
+        assert!(!verify_login("", None));
+    }
+
+    #[test]
+    fn admin_has_every_role() {
+        assert!(Role::Admin.has(Role::ProblemSetter));
+        assert!(Role::ProblemSetter.has(Role::Contestant));
+        assert!(!Role::Contestant.has(Role::ProblemSetter));
+    }
+}
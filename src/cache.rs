@@ -0,0 +1,160 @@
+use crate::Language;
+use blake2::{Blake2s256, Digest};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+///suffix of the compiler messages kept with a cached program
+const INFO_SUFFIX: &str = ".info";
+///suffix of a program being put into the cache
+const TEMP_SUFFIX: &str = ".tmp";
+
+///Key of a build of source by language, a hash of the build command, the file name and source,
+/// and of the compiler the command runs, so that programs built by another version of it are not used.
+/// The command is taken before its %SOURCE%, %OUTPUT% and %DIR% are filled, which differ from job to job.
+pub fn key(language: &Language, source: &str) -> String {
+    let mut hasher = Blake2s256::new();
+    let compiler = language.command.first().map(|x| compiler(x)).unwrap_or_default();
+    let parts = language.command.iter().chain([&language.name, &language.file_name, &compiler]);
+    for part in parts.map(|x| x.as_bytes()).chain([source.as_bytes()]) {
+        //lengths keep ["ab", "c"] and ["a", "bc"] apart
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    hasher.finalize().iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+///The file a command runs, found in PATH like the shell does, with its size and time of modification,
+/// which change when the compiler is upgraded. Empty if it isn't found.
+fn compiler(command: &str) -> String {
+    let path = if command.contains('/') {
+        Some(PathBuf::from(command))
+    } else {
+        env::var_os("PATH").and_then(|paths| env::split_paths(&paths).map(|x| x.join(command)).find(|x| x.is_file()))
+    };
+    //links such as /usr/bin/cc lead to the compiler itself
+    let Some(path) = path.and_then(|x| fs::canonicalize(x).ok()) else {
+        return String::new();
+    };
+    let Ok(metadata) = fs::metadata(&path) else {
+        return String::new();
+    };
+    let modified = metadata.modified().ok().and_then(|x| x.duration_since(SystemTime::UNIX_EPOCH).ok()).unwrap_or_default();
+    format!("{} {} {}", path.display(), metadata.len(), modified.as_nanos())
+}
+
+///Copy the program cached under key to output, returning the compiler messages kept with it, None if not cached.
+pub fn fetch(dir: &Path, key: &str, output: &Path) -> Option<String> {
+    let program = dir.join(key);
+    let info = fs::read_to_string(dir.join(format!("{}{}", key, INFO_SUFFIX))).ok()?;
+    fs::copy(&program, output).ok()?;
+    //used just now, the last to be evicted
+    if let Ok(file) = fs::File::options().append(true).open(&program) {
+        file.set_modified(SystemTime::now()).ok();
+    }
+    Some(info)
+}
+
+///Keep a copy of a built program and its compiler messages under key, then evict the least recently used programs
+/// until the cache takes at most size bytes.
+pub fn store(dir: &Path, key: &str, program: &Path, info: &str, size: u64) -> io::Result<()> {
+    static TEMP_ID: AtomicU64 = AtomicU64::new(0);
+    fs::create_dir_all(dir)?;
+    //the messages and the program show up at once and complete, another worker may be looking for them
+    let temp = dir.join(format!("{}.{}{}", key, TEMP_ID.fetch_add(1, Ordering::SeqCst), TEMP_SUFFIX));
+    fs::write(&temp, info)?;
+    fs::rename(&temp, dir.join(format!("{}{}", key, INFO_SUFFIX)))?;
+    fs::copy(program, &temp)?;
+    fs::rename(&temp, dir.join(key))?;
+    evict(dir, size)
+}
+
+///Remove programs from the oldest used on, until the cache takes at most size bytes.
+fn evict(dir: &Path, size: u64) -> io::Result<()> {
+    let mut programs = Vec::new();
+    let mut total = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.ends_with(INFO_SUFFIX) || name.ends_with(TEMP_SUFFIX) {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        let info_len = fs::metadata(dir.join(format!("{}{}", name, INFO_SUFFIX))).map_or(0, |x| x.len());
+        total += metadata.len() + info_len;
+        programs.push((metadata.modified()?, name, metadata.len() + info_len));
+    }
+    programs.sort();
+    for (_, name, len) in programs {
+        if total <= size {
+            break;
+        }
+        //another worker may have removed it already
+        fs::remove_file(dir.join(&name)).ok();
+        fs::remove_file(dir.join(format!("{}{}", name, INFO_SUFFIX))).ok();
+        total -= len;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn rust() -> Language {
+        serde_json::from_value(serde_json::json!({
+            "name": "Rust", "file_name": "main.rs", "command": ["rustc", "%INPUT%", "-o", "%OUTPUT%"]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn key_differs_by_source_and_command() {
+        let mut language = rust();
+        let key = super::key(&language, "fn main() {}");
+        assert_eq!(key, super::key(&language, "fn main() {}"));
+        assert_ne!(key, super::key(&language, "fn main() { }"));
+        language.command.push("-O".to_string());
+        assert_ne!(key, super::key(&language, "fn main() {}"));
+    }
+
+    #[test]
+    fn key_differs_by_compiler() {
+        let dir = std::env::temp_dir().join(format!("oj-cache-compiler-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let compiler = dir.join("cc");
+        fs::write(&compiler, "old").unwrap();
+        let mut language = rust();
+        language.command[0] = compiler.display().to_string();
+        let key = super::key(&language, "fn main() {}");
+        fs::write(&compiler, "upgraded").unwrap();
+        assert_ne!(key, super::key(&language, "fn main() {}"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let dir = std::env::temp_dir().join(format!("oj-cache-test-{}", std::process::id()));
+        let program = dir.join("main");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&program, [0u8; 100]).unwrap();
+        let cache = dir.join("cache");
+        store(&cache, "a", &program, "", 250).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        store(&cache, "b", &program, "warning", 250).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        //a is used again, so b goes first
+        assert_eq!(fetch(&cache, "a", &dir.join("a")), Some(String::new()));
+        std::thread::sleep(Duration::from_millis(20));
+        store(&cache, "c", &program, "", 250).unwrap();
+        assert!(fetch(&cache, "b", &dir.join("b")).is_none());
+        assert!(fetch(&cache, "a", &dir.join("a")).is_some());
+        assert!(fetch(&cache, "c", &dir.join("c")).is_some());
+        assert_eq!(fs::read(dir.join("c")).unwrap(), [0u8; 100]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
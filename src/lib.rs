@@ -1,19 +1,45 @@
 use std::cmp::Ordering;
 use clap::Parser;
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::fs;
-use std::fs::create_dir;
 use std::io::{Read, Write};
-use std::process::{Command, Stdio};
+use std::path::{Path, PathBuf};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus, Stdio};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::time::Duration;
-use actix_web::web;
-use chrono::{DateTime, FixedOffset, Utc};
-use wait_timeout::ChildExt;
+use chrono::{DateTime, FixedOffset, NaiveDateTime, Utc};
 use actix_web::HttpResponse;
+use auth::{Auth, Role};
+use runner::{Limit, Termination};
+use sandbox::Sandbox;
+
+pub mod auth;
+pub mod cache;
+pub mod problem;
+pub mod runner;
+pub mod sandbox;
+pub mod storage;
 
 
 pub const TIME_FMT: &str = "%Y-%m-%dT%H:%M:%S%.3fZ";
+///bytes of checker messages kept in case info
+const CHECKER_INFO_LIMIT: usize = 4096;
+///time an interactor may run longer than the program, to give its result
+const INTERACTOR_GRACE: Duration = Duration::from_secs(1);
+///time a special judge or checker may take on a case
+const CHECKER_TIME_LIMIT: Duration = Duration::from_secs(10);
+///error allowed between numbers of a float problem if its misc doesn't tell
+const EPSILON_DEFAULT: f64 = 1e-6;
+///bytes a program may print on a case if neither the case nor the problem tells
+const OUTPUT_LIMIT_DEFAULT: u64 = 64 << 20;
+///bytes of the end of stderr kept in the info of a runtime error
+const STDERR_TAIL_LIMIT: usize = 1024;
+///times the cpu time limit a case may take on the clock if it doesn't tell
+const WALL_TIME_FACTOR: f64 = 3.0;
 
 ///two args, parse by clap
 #[derive(Parser, Debug)]
@@ -25,49 +51,141 @@ pub struct Args {
     pub flush_data: bool,
 }
 
-///address of server
+///addresses of server, and how many jobs it judges at the same time
 #[derive(Serialize, Deserialize, Clone)]
-struct Server {
+pub struct Server {
     #[serde(default = "address_default")]
-    bind_address: String,
+    pub bind_address: String,
     #[serde(default = "port_default")]
-    bind_port: i32,
+    pub bind_port: u16,
+    ///more "address:port" to listen on
+    #[serde(default)]
+    pub extra_binds: Vec<String>,
+    ///path of a unix domain socket to listen on as well, such as for a reverse proxy
+    #[serde(default)]
+    pub unix_socket: Option<String>,
+    ///sqlite database file keeping jobs, users and contests
+    #[serde(default = "database_default")]
+    pub database: String,
+    ///directory test data uploaded to /problems/{id}/data is kept in
+    #[serde(default = "problem_data_default")]
+    pub problem_data: String,
+    ///directory the %OUTPUT% of builds is kept in, so that rejudging or submitting the same code doesn't build it again
+    #[serde(default = "compile_cache_default")]
+    pub compile_cache: String,
+    ///bytes the compile cache may take before the least recently used programs are dropped, 0 to disable it
+    #[serde(default = "compile_cache_size_default")]
+    pub compile_cache_size: u64,
+    ///directory jobs are judged in, of this server alone, as what's left there is removed at start up
+    #[serde(default = "judging_dir_default")]
+    pub judging_dir: String,
+    #[serde(default = "judge_workers_default")]
+    pub judge_workers: usize,
 }
 
 fn address_default() -> String {
     "127.0.0.1".to_string()
 }
 
-fn port_default() -> i32 {
+fn port_default() -> u16 {
     12345
 }
 
+fn database_default() -> String {
+    "oj.db".to_string()
+}
+
+fn problem_data_default() -> String {
+    "problem_data".to_string()
+}
+
+fn compile_cache_default() -> String {
+    "compile_cache".to_string()
+}
+
+fn compile_cache_size_default() -> u64 {
+    256 << 20
+}
+
+fn judging_dir_default() -> String {
+    "judging".to_string()
+}
+
+fn judge_workers_default() -> usize {
+    std::thread::available_parallelism().map_or(1, |x| x.get())
+}
+
 ///case data in a problem
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Case {
     score: f64,
     input_file: String,
     answer_file: String,
+    ///limit on user and system time, in microseconds
     time_limit: i64,
     memory_limit: i64,
+    ///limit on time taken on the clock, in microseconds, for a program waiting as long as for running
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    wall_time_limit: Option<i64>,
+    ///bytes the program may print, misc.output_limit of the problem if not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    output_limit: Option<u64>,
 }
 
-///a whole problem, give in config
+///a whole problem, given in config, by a problem package or by POST /problems
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Problem {
+    ///given by the server to a posted problem
+    #[serde(default)]
     pub id: i32,
     name: String,
     #[serde(rename = "type")]
     pub ty: ProblemType,
     misc: Misc,
     pub cases: Vec<Case>,
+    ///text of the problem, in markdown
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    statement: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct Misc {
     packing: Option<Vec<Vec<usize>>>,
     special_judge: Option<Vec<String>>,
     dynamic_ranking_ratio: Option<f64>,
+    ///how special_judge tells its result
+    #[serde(default)]
+    checker_protocol: CheckerProtocol,
+    ///source file of a testlib checker, built at start up, used instead of special_judge
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checker: Option<String>,
+    ///command of the interactor of an interactive problem, with %INPUT%, %ANSWER% and %OUTPUT% files,
+    /// talking with the program through its stdin and stdout and telling the result by testlib exit code
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    interactor: Option<Vec<String>>,
+    ///absolute or relative error allowed between numbers of a float problem
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    epsilon: Option<f64>,
+    ///bytes the program may print on a case without its own output_limit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    output_limit: Option<u64>,
+    ///put the end of what the program wrote to stderr into the info of a runtime error, not for interactive problems
+    #[serde(default)]
+    show_stderr: bool,
+    ///judge the output of a program exiting with a non-zero code, instead of calling it a runtime error
+    #[serde(default)]
+    ignore_exit_code: bool,
+}
+
+///how a special judge tells the result of a case
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckerProtocol {
+    ///the first line of stdout names a result such as "Wrong Answer", the second one is info
+    #[default]
+    ResultLine,
+    ///exit code of testlib, with its message on stderr as info
+    Testlib,
 }
 
 ///code language, also contains commands to build a program
@@ -75,15 +193,52 @@ pub struct Misc {
 pub struct Language {
     pub name: String,
     file_name: String,
+    ///build command, no build step if empty
+    #[serde(default)]
     pub command: Vec<String>,
+    ///command running the judged program, the built %OUTPUT% itself if not given
+    #[serde(default)]
+    run: Option<Vec<String>>,
+    ///factors on case time and memory limits, for languages slower or heavier than others
+    #[serde(default = "multiplier_default")]
+    time_multiplier: f64,
+    #[serde(default = "multiplier_default")]
+    memory_multiplier: f64,
+    ///time a build may take, in microseconds
+    #[serde(default = "compile_time_limit_default")]
+    compile_time_limit: u64,
+    ///bytes of compiler output kept in the compilation case
+    #[serde(default = "compile_output_limit_default")]
+    compile_output_limit: usize,
+}
+
+fn multiplier_default() -> f64 {
+    1.0
+}
+
+fn compile_time_limit_default() -> u64 {
+    10_000_000
+}
+
+fn compile_output_limit_default() -> usize {
+    16 * 1024
 }
 
 ///config of whole oj
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
-    server: Server,
+    pub server: Server,
+    ///problems put into the database if it doesn't have them, changed by /problems afterwards
+    #[serde(default)]
     pub problems: Vec<Problem>,
+    ///directories of problem packages, read again at every start up
+    #[serde(default)]
+    pub packages: Vec<String>,
     pub languages: Vec<Language>,
+    #[serde(default)]
+    pub sandbox: Sandbox,
+    #[serde(default)]
+    pub auth: Auth,
 }
 
 /// a post job from a client, contains all information of how to deal with the job
@@ -108,6 +263,67 @@ pub struct GetJob {
     to: Option<String>,
     state: Option<State>,
     result: Option<MyResult>,
+    ///1-based page of per_page jobs, all jobs at once if neither is given
+    pub page: Option<u32>,
+    pub per_page: Option<u32>,
+    #[serde(default)]
+    pub sort_by: JobSortKey,
+    #[serde(default)]
+    pub order: SortOrder,
+}
+
+pub const PER_PAGE_DEFAULT: u32 = 20;
+pub const PER_PAGE_MAX: u32 = 1000;
+
+impl GetJob {
+    ///offset and count of the asked page, if paging is asked
+    pub fn page_range(&self) -> Result<Option<(u32, u32)>, Error> {
+        if self.page.is_none() && self.per_page.is_none() {
+            return Ok(None);
+        }
+        let page = self.page.unwrap_or(1);
+        let per_page = self.per_page.unwrap_or(PER_PAGE_DEFAULT);
+        if page == 0 || per_page == 0 || per_page > PER_PAGE_MAX {
+            return Err(Error {
+                reason: Reason::ErrInvalidArgument,
+                code: 1,
+                message: format!("page must be positive, and per_page between 1 and {}", PER_PAGE_MAX),
+            });
+        }
+        Ok(Some(((page - 1).saturating_mul(per_page), per_page)))
+    }
+
+    ///check that from and to are times in TIME_FMT, which match_job needs
+    pub fn check_times(&self) -> Result<(), Error> {
+        for time in [&self.from, &self.to].into_iter().flatten() {
+            if NaiveDateTime::parse_from_str(time, TIME_FMT).is_err() {
+                return Err(Error {
+                    reason: Reason::ErrInvalidArgument,
+                    code: 1,
+                    message: format!("time '{}' is not like 2022-08-27T02:05:29.000Z", time),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+///what job lists are sorted by
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum JobSortKey {
+    #[default]
+    Id,
+    CreatedTime,
+    Score,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
 }
 
 ///the test result of a single result
@@ -115,9 +331,16 @@ pub struct GetJob {
 pub struct CaseResult {
     id: i32,
     result: MyResult,
+    ///user and system time in microseconds, of the compiler for case 0
     time: i32,
-    memory: i32,
+    ///time taken on the clock in microseconds
+    #[serde(default)]
+    wall_time: i32,
+    memory: i64,
     info: String,
+    ///part of the case score earned, the lowest ratio of its pack
+    #[serde(default)]
+    score: f64,
 }
 
 impl CaseResult {
@@ -126,8 +349,10 @@ impl CaseResult {
             id,
             result: MyResult::Waiting,
             time: 0,
+            wall_time: 0,
             memory: 0,
             info: "".to_string(),
+            score: 0.0,
         }
     }
 }
@@ -154,7 +379,7 @@ pub struct Job {
     cases: Vec<CaseResult>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Error {
     pub reason: Reason,
     pub code: i32,
@@ -170,6 +395,9 @@ impl Error {
             Reason::ErrNotFound => {
                 HttpResponse::NotFound().json(self)
             }
+            Reason::ErrInvalidState => {
+                HttpResponse::BadRequest().json(self)
+            }
             Reason::ErrRateLimit => {
                 HttpResponse::BadRequest().json(self)
             }
@@ -179,6 +407,12 @@ impl Error {
             Reason::ErrInternal => {
                 HttpResponse::InternalServerError().json(self)
             }
+            Reason::ErrUnauthorized => {
+                HttpResponse::Unauthorized().json(self)
+            }
+            Reason::ErrForbidden => {
+                HttpResponse::Forbidden().json(self)
+            }
         }
     }
 }
@@ -189,6 +423,12 @@ fn my_now() -> String {
 }
 
 impl Job {
+    ///the same job with its source code hidden
+    pub fn without_source(&self) -> Job {
+        let mut job = self.clone();
+        job.submission.source_code = String::new();
+        job
+    }
     pub fn new(id: i32, post: &PostJob) -> Job {
         Job {
             id,
@@ -201,6 +441,41 @@ impl Job {
             cases: vec![],
         }
     }
+    ///clear a job's result and put it back into queue, with one waiting case for compilation and each test case
+    pub fn reset(&mut self, case_count: usize) {
+        self.updated_time = my_now();
+        self.state = State::Queueing;
+        self.result = MyResult::Waiting;
+        self.score = 0.0;
+        self.cases = (0..=case_count as i32).map(CaseResult::new).collect();
+    }
+    ///whether a job is waiting for or under judging
+    pub fn is_pending(&self) -> bool {
+        self.state == State::Queueing || self.state == State::Running
+    }
+    ///give up judging a job because of an unexpected failure, and tell why in the compilation case
+    pub fn system_error(&mut self, info: &str) {
+        self.result = MyResult::SystemError;
+        if let Some(case) = self.cases.get_mut(0) {
+            case.result = MyResult::SystemError;
+            case.info = info.to_string();
+        }
+        self.final_result();
+    }
+    pub fn is_canceled(&self) -> bool {
+        self.state == State::Canceled
+    }
+    ///stop judging a job for good, cases not judged yet are skipped
+    pub fn cancel(&mut self) {
+        self.updated_time = my_now();
+        self.state = State::Canceled;
+        self.result = MyResult::Skipped;
+        for case in self.cases.iter_mut() {
+            if case.result == MyResult::Waiting || case.result == MyResult::Running {
+                case.result = MyResult::Skipped;
+            }
+        }
+    }
     ///refresh a job's updated time
     fn update(&mut self) {
         self.updated_time = my_now();
@@ -213,6 +488,94 @@ impl Job {
     }
 }
 
+///a batch of jobs rejudged at once by POST /jobs/rejudge, with the verdicts they had before
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Rejudge {
+    pub id: i32,
+    created_time: String,
+    ///filter of get_jobs the jobs were chosen by
+    filter: GetJob,
+    jobs: Vec<Rejudged>,
+    ///jobs chosen but left alone, as they were still being judged or their problem is gone
+    skipped: Vec<i32>,
+}
+
+///a job of a rejudge, with its verdict before
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Rejudged {
+    job_id: i32,
+    previous_result: MyResult,
+    previous_score: f64,
+}
+
+///how far a rejudge got, and the verdict of each job before and now
+#[derive(Serialize)]
+pub struct RejudgeProgress {
+    id: i32,
+    created_time: String,
+    filter: GetJob,
+    total: usize,
+    finished: usize,
+    ///finished jobs whose result or score is not the one before
+    changed: usize,
+    jobs: Vec<RejudgedJob>,
+    skipped: Vec<i32>,
+}
+
+///a job in the progress of a rejudge
+#[derive(Serialize)]
+pub struct RejudgedJob {
+    job_id: i32,
+    state: State,
+    previous_result: MyResult,
+    result: MyResult,
+    previous_score: f64,
+    score: f64,
+}
+
+impl Rejudge {
+    pub fn new(id: i32, filter: GetJob) -> Rejudge {
+        Rejudge { id, created_time: my_now(), filter, jobs: vec![], skipped: vec![] }
+    }
+    ///remember the verdict of a job before it's reset for judging again
+    pub fn add(&mut self, job: &Job) {
+        self.jobs.push(Rejudged { job_id: job.id, previous_result: job.result.clone(), previous_score: job.score });
+    }
+    pub fn skip(&mut self, job: &Job) {
+        self.skipped.push(job.id);
+    }
+    ///the progress of the rejudge, by the jobs as they are now; deleted jobs are left out
+    pub fn progress(&self, jobs: &[Job]) -> RejudgeProgress {
+        let jobs: Vec<RejudgedJob> = self
+            .jobs
+            .iter()
+            .filter_map(|x| {
+                let job = jobs.iter().find(|y| y.id == x.job_id)?;
+                Some(RejudgedJob {
+                    job_id: x.job_id,
+                    state: job.state.clone(),
+                    previous_result: x.previous_result.clone(),
+                    result: job.result.clone(),
+                    previous_score: x.previous_score,
+                    score: job.score,
+                })
+            })
+            .collect();
+        let finished: Vec<&RejudgedJob> =
+            jobs.iter().filter(|x| x.state != State::Queueing && x.state != State::Running).collect();
+        RejudgeProgress {
+            id: self.id,
+            created_time: self.created_time.clone(),
+            filter: self.filter.clone(),
+            total: jobs.len(),
+            finished: finished.len(),
+            changed: finished.iter().filter(|x| x.result != x.previous_result || x.score != x.previous_score).count(),
+            jobs,
+            skipped: self.skipped.clone(),
+        }
+    }
+}
+
 ///All possible result of a job or a case
 #[derive(Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub enum MyResult {
@@ -225,16 +588,24 @@ pub enum MyResult {
     CompilationSuccess,
     #[serde(rename = "Wrong Answer")]
     WrongAnswer,
+    #[serde(rename = "Presentation Error")]
+    PresentationError,
+    #[serde(rename = "Partially Correct")]
+    PartiallyCorrect,
     #[serde(rename = "Runtime Error")]
     RuntimeError,
     #[serde(rename = "Time Limit Exceeded")]
     TimeLimitExceeded,
     #[serde(rename = "Memory Limit Exceeded")]
     MemoryLimitExceeded,
+    #[serde(rename = "Output Limit Exceeded")]
+    OutputLimitExceeded,
     #[serde(rename = "System Error")]
     SystemError,
     #[serde(rename = "SPJ Error")]
     SPJError,
+    #[serde(rename = "Sandbox Violation")]
+    SandboxViolation,
     Skipped,
 }
 
@@ -244,26 +615,48 @@ pub enum ProblemType {
     Standard,
     Strict,
     Spj,
+    ///tokens compared one by one, numbers equal within misc.epsilon
+    Float,
+    ///tokens compared one by one, whatever whitespace is between them
+    Tokens,
+    ///lines compared as in standard, ignoring case
+    CaseInsensitive,
+    ///the same lines as the answer in any order, empty ones ignored
+    UnorderedLines,
+    ///the same tokens as the answer in any order
+    UnorderedTokens,
     DynamicRanking,
+    Interactive,
 }
 
 /// reasons why a request failed
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Reason {
     #[serde(rename = "ERR_INVALID_ARGUMENT")]
     ErrInvalidArgument,
     #[serde(rename = "ERR_NOT_FOUND")]
     ErrNotFound,
+    #[serde(rename = "ERR_INVALID_STATE")]
+    ErrInvalidState,
     #[serde(rename = "ERR_RATE_LIMIT")]
     ErrRateLimit,
     ErrExternal,
     ErrInternal,
+    #[serde(rename = "ERR_UNAUTHORIZED")]
+    ErrUnauthorized,
+    #[serde(rename = "ERR_FORBIDDEN")]
+    ErrForbidden,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct User {
     pub id: Option<i32>,
     pub name: String,
+    #[serde(default)]
+    pub role: Role,
+    ///only ever received, to set the password
+    #[serde(default, skip_serializing)]
+    pub password: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -316,17 +709,94 @@ pub struct Contest {
     pub submission_limit: i32,
 }
 
-///Receive a job, config, contest_list, and job_list, return a updated job
+///Remove judging directories left behind in root by a previous run of the server.
+pub fn sweep_job_dirs(root: &Path) {
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        if entry.file_name().to_string_lossy().starts_with("job") {
+            if let Err(err) = fs::remove_dir_all(entry.path()) {
+                log::warn!("failed to remove stale {}: {}", entry.path().display(), err);
+            }
+        }
+    }
+}
+
+///directory where a job is judged, removed with everything in it when dropped, even on panic
+pub struct JobDir {
+    path: PathBuf,
+}
+
+impl JobDir {
+    ///create an empty directory for a job in root, replacing whatever an earlier judging of the job left
+    pub fn create(root: &Path, job_id: i32) -> std::io::Result<JobDir> {
+        let path = root.join(format!("job{}", job_id));
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        fs::create_dir_all(&path)?;
+        //programs run in the directory, paths into it must not be relative
+        Ok(JobDir { path: path.canonicalize()? })
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for JobDir {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.path) {
+            log::warn!("failed to remove {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+///jobs waiting for a judge worker, in submission order
+#[derive(Default)]
+pub struct JobQueue {
+    ids: Mutex<VecDeque<i32>>,
+    ready: Condvar,
+}
+
+impl JobQueue {
+    ///put a job at the end of queue, and wake up a waiting worker
+    pub fn push(&self, id: i32) {
+        self.ids.lock().unwrap().push_back(id);
+        self.ready.notify_one();
+    }
+    ///take a job out of queue, return whether it was there
+    pub fn remove(&self, id: i32) -> bool {
+        let mut ids = self.ids.lock().unwrap();
+        let len = ids.len();
+        ids.retain(|x| *x != id);
+        ids.len() != len
+    }
+    ///take the first job in queue, block until there is one
+    pub fn pop(&self) -> i32 {
+        let mut ids = self.ids.lock().unwrap();
+        loop {
+            match ids.pop_front() {
+                Some(id) => return id,
+                None => ids = self.ready.wait(ids).unwrap(),
+            }
+        }
+    }
+}
+
+///Receive a job, config, problems, contest_list, and job_list, return the problem of the job
 /// it will return error if job is illegal.
-pub fn run_job(
-    job: &mut Job,
-    config: &web::Data<Config>,
-    contest_list: &Vec<Contest>,
-    job_list: Vec<Job>,
-) -> Result<Job, Error> {
+pub fn check_job<'a>(
+    job: &Job,
+    config: &Config,
+    problems: &'a [Problem],
+    contest_list: &[Contest],
+    job_list: &[Job],
+) -> Result<&'a Problem, Error> {
     //check
-    let current_language = config.languages.iter().find(|x| x.name == job.submission.language).cloned();
-    let problem = config.problems.iter().find(|x| x.id == job.submission.problem_id);
+    let current_language = config.languages.iter().find(|x| x.name == job.submission.language);
+    let problem = problems.iter().find(|x| x.id == job.submission.problem_id);
     let contest = contest_list.iter().find(|x| x.id.unwrap() == job.submission.contest_id);
     if current_language.is_none() || problem.is_none() {
         return Err(Error {
@@ -368,58 +838,111 @@ pub fn run_job(
             });
         }
     }
+    Ok(problem.unwrap())
+}
 
-
+///Receive a checked job and config, judge it and leave the result in job.
+/// report is called with the job every time its state or a case result changes.
+/// Once canceled is set, the running program is killed and the job ends canceled.
+pub fn run_job(
+    job: &mut Job,
+    config: &Config,
+    problems: &[Problem],
+    canceled: &Arc<AtomicBool>,
+    report: &mut dyn FnMut(&Job),
+) -> Result<(), Error> {
+    let current_language = config.languages.iter().find(|x| x.name == job.submission.language).cloned();
+    let problem = problems.iter().find(|x| x.id == job.submission.problem_id);
+    if current_language.is_none() {
+        return Err(Error {
+            reason: Reason::ErrNotFound,
+            code: 3,
+            message: format!("language {} not found", job.submission.language),
+        });
+    }
+    if problem.is_none() {
+        return Err(Error {
+            reason: Reason::ErrNotFound,
+            code: 3,
+            message: format!("problem{} not found", job.submission.problem_id),
+        });
+    }
     let mut current_language = current_language.unwrap();
     let problem = problem.unwrap();
+    let checker = match &problem.misc.checker {
+        Some(_) => Some(problem::checker_command(problem, config)?),
+        None => None,
+    };
 
     //if uninitialized, let first error become job_result
     let mut job_result: Option<MyResult> = None;
 
     //initialize job cases, clear and push default
-    {
-        job.result = MyResult::Waiting;
-        job.score = 0.0;
-        job.cases.clear();
-        let mut count = 0;
-        job.cases.push(CaseResult::new(count));
-        for _case in &problem.cases {
-            count += 1;
-            job.cases.push(CaseResult::new(count));
-        }
-    }
+    job.reset(problem.cases.len());
+    job.result = MyResult::Running;
+    job.cases[0].result = MyResult::Running;
+    job.update();
+    report(job);
 
-    //replace %INPUT% and %OUTPUT% of language
-    let dir_path = format!("./problem{}", job.submission.problem_id);
-    let input_index = current_language
-        .command
-        .iter()
-        .position(|x| x == "%INPUT%")
-        .unwrap();
+    let job_dir = JobDir::create(Path::new(&config.server.judging_dir), job.id).map_err(|err| Error {
+        reason: Reason::ErrInternal,
+        code: 6,
+        message: format!("failed to create judging directory: {}", err),
+    })?;
+    let dir_path = job_dir.path().to_string_lossy().to_string();
     let file_path = format!("{}/{}", dir_path, current_language.file_name);
-    current_language.command[input_index] = file_path.clone();
-    let output_index = current_language
-        .command
-        .iter()
-        .position(|x| x == "%OUTPUT%")
-        .unwrap();
-    let out_path = format!("{}/job_{}", dir_path, job.submission.user_id);
-    current_language.command[output_index] = out_path.clone();
+    let out_path = format!("{}/main", dir_path);
+    //replace %SOURCE% (or %INPUT%), %OUTPUT% and %DIR% of language
+    let fill = |template: &Vec<String>| -> Vec<String> {
+        template
+            .iter()
+            .map(|x| {
+                x.replace("%INPUT%", &file_path)
+                    .replace("%SOURCE%", &file_path)
+                    .replace("%OUTPUT%", &out_path)
+                    .replace("%DIR%", &dir_path)
+            })
+            .collect()
+    };
+    let cache_key = cache::key(&current_language, &job.submission.source_code);
+    current_language.command = fill(&current_language.command);
+    let run_command = match &current_language.run {
+        Some(run) => fill(run),
+        None => vec![out_path.clone()],
+    };
 
     //start to compile
-    create_dir(&dir_path).unwrap();
     fs::File::create(&file_path).unwrap();
-    fs::write(file_path, &job.submission.source_code).unwrap();
-    let build_time = std::time::Instant::now();
-    let build_job = Command::new(&current_language.command[0])
-        .args(&current_language.command[1..])
-        .status()
-        .unwrap();
-    job.cases[0].time = build_time.elapsed().as_micros() as i32;
-    if build_job.code() != Some(0) {
+    fs::write(&file_path, &job.submission.source_code).unwrap();
+    let cache_dir = Path::new(&config.server.compile_cache);
+    let use_cache = config.server.compile_cache_size != 0 && !current_language.command.is_empty();
+    let build_termination = if current_language.command.is_empty() {
+        Termination::Exited(0)
+    } else if let Some(info) = use_cache.then(|| cache::fetch(cache_dir, &cache_key, Path::new(&out_path))).flatten() {
+        job.cases[0].info = info;
+        Termination::Exited(0)
+    } else {
+        let termination = build(&current_language, &config.sandbox, &dir_path, canceled, &mut job.cases[0])?;
+        if use_cache && termination == Termination::Exited(0) {
+            let stored = cache::store(cache_dir, &cache_key, Path::new(&out_path), &job.cases[0].info, config.server.compile_cache_size);
+            if let Err(err) = stored {
+                log::warn!("Failed to cache build of job {}: {}", job.id, err);
+            }
+        }
+        termination
+    };
+    if canceled.load(AtomicOrdering::SeqCst) {
+        job.cancel();
+        report(job);
+        return Ok(());
+    }
+    if build_termination != Termination::Exited(0) {
         job_result = Some(MyResult::CompilationError);
 
         job.cases[0].result = MyResult::CompilationError;
+        if build_termination == Termination::TimedOut {
+            job.cases[0].info.push_str("\ncompilation timed out");
+        }
         job.final_result();
         //if compile error
     } else {
@@ -427,154 +950,445 @@ pub fn run_job(
         //compile succeed
         job.cases[0].result = MyResult::CompilationSuccess;
         job.update();
+        report(job);
         let packing: Vec<Vec<usize>>;
         match problem.misc.packing.clone() {
             None => packing = vec![(1..=problem.cases.len()).collect()],
             Some(p) => packing = p
         }
 
-        //case by case
+        //case by case, a pack earns the lowest ratio of its cases' scores
+        let dynamic_ratio = problem.misc.dynamic_ranking_ratio.unwrap_or(0.0);
         for pack in packing {
-            let mut is_pack_accepted = true;
-            let mut pack_score = 0.0;
-            for case_id in pack {
-                let case_timeing = std::time::Instant::now();
-                let case_time: Duration;
+            let mut pack_ratio: f64 = 1.0;
+            for case_id in pack.iter().copied() {
                 //value to record result
                 let mut case_result = CaseResult::new(case_id as i32);
-                if is_pack_accepted {
-                    case_result = run_one_case(&problem, &out_path, case_id);
+                if pack_ratio > 0.0 {
+                    job.cases[case_id].result = MyResult::Running;
+                    report(job);
+                    let ratio;
+                    (case_result, ratio) = run_one_case(
+                        &problem,
+                        checker.as_deref(),
+                        &current_language,
+                        &config.sandbox,
+                        &dir_path,
+                        &run_command,
+                        canceled,
+                        case_id,
+                    );
                     //let first wrong case result be job result, decide whether go on
-                    match case_result.result {
-                        MyResult::Accepted => {
-                            let ratio = problem.misc.dynamic_ranking_ratio.unwrap_or_else(|| 0.0);
-                            pack_score += problem.cases[case_id - 1].score * (1.0 - ratio);
-                        }
-                        _ => {
-                            if job_result.is_none() {
-                                job_result = Some(case_result.result.clone())
-                            }
-                            is_pack_accepted = false;
-                            pack_score = 0.0;
-                        }
+                    if case_result.result != MyResult::Accepted && job_result.is_none() {
+                        job_result = Some(case_result.result.clone())
                     }
+                    pack_ratio = pack_ratio.min(ratio);
                 } else {
                     case_result.result = MyResult::Skipped;
                 }
-                case_time = case_timeing.elapsed();
-                case_result.time = case_time.as_micros() as i32;
                 job.cases[case_id] = case_result;
+                if canceled.load(AtomicOrdering::SeqCst) {
+                    job.cancel();
+                    report(job);
+                    return Ok(());
+                }
                 job.update();
+                report(job);
+            }
+            for case_id in pack {
+                job.cases[case_id].score = problem.cases[case_id - 1].score * pack_ratio * (1.0 - dynamic_ratio);
+                job.score += job.cases[case_id].score;
             }
-            job.score += pack_score;
             job.update();
         }
     }
-    fs::remove_dir_all(&dir_path).unwrap();
-    if let Some(r) = job_result {
-        job.result = r;
-    }
-    if job.score == 100.0 * (1.0 - problem.misc.dynamic_ranking_ratio.unwrap_or(0.0)) {
-        job.result = MyResult::Accepted;
+    match job_result {
+        Some(r) => job.result = r,
+        None => job.result = MyResult::Accepted,
     }
     job.final_result();
-    Ok(job.clone())
+    report(job);
+    Ok(())
+}
+
+///Run the build command of a language in dir_path, keeping compiler output in the compilation case.
+fn build(
+    language: &Language,
+    sandbox: &Sandbox,
+    dir_path: &str,
+    canceled: &Arc<AtomicBool>,
+    case: &mut CaseResult,
+) -> Result<Termination, Error> {
+    let limit = Limit { time: Duration::from_micros(language.compile_time_limit), cpu: Duration::ZERO, memory: 0 };
+    let mut command = Command::new(&language.command[0]);
+    command.args(&language.command[1..]).stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    let jail = if sandbox.enabled && sandbox.compile {
+        Some(sandbox.confine(&mut command, Path::new(dir_path)).map_err(|err| Error {
+            reason: Reason::ErrInternal,
+            code: 6,
+            message: format!("failed to prepare sandbox: {}", err),
+        })?)
+    } else {
+        None
+    };
+    let usage = runner::spawn(&mut command, &limit, jail).and_then(|mut process| {
+        let output_limit = language.compile_output_limit;
+        let stdout = read_capped(process.child.stdout.take().unwrap(), output_limit);
+        let stderr = read_capped(process.child.stderr.take().unwrap(), output_limit);
+        let usage = runner::wait(&mut process, &limit, canceled)?;
+        let mut output = stdout.join().unwrap_or_default();
+        output.extend(stderr.join().unwrap_or_default());
+        case.info = capped_text(&output, output_limit);
+        Ok(usage)
+    }).map_err(|err| Error {
+        reason: Reason::ErrInternal,
+        code: 6,
+        message: format!("failed to run compiler: {}", err),
+    })?;
+    case.time = usage.cpu_time.as_micros() as i32;
+    case.wall_time = usage.wall_time.as_micros() as i32;
+    Ok(usage.termination)
+}
+
+///Read all of a pipe aside so that the writer never blocks, keeping at most limit bytes.
+fn read_capped<R: Read + Send + 'static>(mut reader: R, limit: usize) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut kept = Vec::new();
+        let mut buffer = [0u8; 4096];
+        while let Ok(n) = reader.read(&mut buffer) {
+            if n == 0 {
+                break;
+            }
+            let room = limit.saturating_sub(kept.len());
+            kept.extend_from_slice(&buffer[..n.min(room + 1)]);
+        }
+        kept
+    })
 }
 
-///Given problem, program path, and case id, run one case and return a result of the case.
-fn run_one_case(problem: &Problem, out_path: &String, case_id: usize) -> CaseResult {
+///Read all of a pipe aside like read_capped(), keeping the last limit bytes and whether some were dropped.
+fn read_tail<R: Read + Send + 'static>(mut reader: R, limit: usize) -> std::thread::JoinHandle<(Vec<u8>, bool)> {
+    std::thread::spawn(move || {
+        let mut kept = Vec::new();
+        let mut dropped = false;
+        let mut buffer = [0u8; 4096];
+        while let Ok(n) = reader.read(&mut buffer) {
+            if n == 0 {
+                break;
+            }
+            kept.extend_from_slice(&buffer[..n]);
+            if kept.len() > 2 * limit {
+                kept.drain(..kept.len() - limit);
+                dropped = true;
+            }
+        }
+        if kept.len() > limit {
+            kept.drain(..kept.len() - limit);
+            dropped = true;
+        }
+        (kept, dropped)
+    })
+}
+
+///Turn output into text of at most limit bytes, telling if some was cut off.
+fn capped_text(output: &[u8], limit: usize) -> String {
+    if output.len() <= limit {
+        return String::from_utf8_lossy(output).to_string();
+    }
+    format!("{}\n(output truncated)", String::from_utf8_lossy(&output[..limit]))
+}
+
+///Given problem, its checker command, language, sandbox, judging directory, run command, and case id,
+/// run one case and return a result of the case, with the ratio of the case score it earns.
+#[allow(clippy::too_many_arguments)]
+fn run_one_case(
+    problem: &Problem,
+    checker: Option<&[String]>,
+    language: &Language,
+    sandbox: &Sandbox,
+    dir_path: &str,
+    run_command: &[String],
+    canceled: &Arc<AtomicBool>,
+    case_id: usize,
+) -> (CaseResult, f64) {
     let case = &problem.cases[case_id - 1];
     let mut case_result = CaseResult::new(case_id as i32);
-    let mut run_case = Command::new(&out_path)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-        .unwrap();
-    run_case
-        .stdin
-        .take()
-        .unwrap()
-        .write(fs::read_to_string(&case.input_file).unwrap().as_bytes())
-        .unwrap();
-    let time_limit = Duration::from_micros(case.time_limit as u64);
-
-    //use time limit, get case result
-    match run_case.wait_timeout(time_limit).unwrap() {
-        None => {
-            run_case.kill().unwrap();
+    let mut ratio = 0.0;
+    let cpu = case.time_limit as f64 * language.time_multiplier;
+    let wall = case.wall_time_limit.map_or(cpu * WALL_TIME_FACTOR, |x| x as f64 * language.time_multiplier);
+    let limit = Limit {
+        time: Duration::from_micros(wall as u64),
+        cpu: Duration::from_micros(cpu as u64),
+        memory: (case.memory_limit as f64 * language.memory_multiplier) as u64,
+    };
+    let jail = if sandbox.enabled {
+        match sandbox.jail(Path::new(dir_path), Path::new(dir_path)) {
+            Ok(jail) => Some(jail),
+            Err(err) => {
+                case_result.result = MyResult::SystemError;
+                case_result.info = format!("failed to prepare sandbox: {}", err);
+                return (case_result, 0.0);
+            }
+        }
+    } else {
+        None
+    };
+    let show_stderr = problem.misc.show_stderr && !matches!(problem.ty, ProblemType::Interactive);
+    let mut command = Command::new(&run_command[0]);
+    command.args(&run_command[1..]).current_dir(dir_path).stdin(Stdio::piped()).stdout(Stdio::piped());
+    if show_stderr {
+        command.stderr(Stdio::piped());
+    }
+    let mut run_case = match runner::spawn(&mut command, &limit, jail) {
+        Ok(process) => process,
+        Err(err) => {
+            case_result.result = MyResult::SystemError;
+            case_result.info = format!("failed to run program: {}", err);
+            return (case_result, 0.0);
+        }
+    };
+    if let ProblemType::Interactive = problem.ty {
+        return interact(problem, case, sandbox, dir_path, run_case, &limit, canceled, case_result);
+    }
+    //read output while the program runs, stopping past the limit so that its next write fails
+    let output_limit = case.output_limit.or(problem.misc.output_limit).unwrap_or(OUTPUT_LIMIT_DEFAULT);
+    let stdout = run_case.child.stdout.take().unwrap();
+    let stderr = run_case.child.stderr.take().map(|x| read_tail(x, STDERR_TAIL_LIMIT));
+    let output = std::thread::spawn(move || -> std::io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        stdout.take(output_limit + 1).read_to_end(&mut output)?;
+        Ok(output)
+    });
+    //feed input aside, a program may exit before reading all of it
+    let input = fs::read(&case.input_file).unwrap();
+    let mut stdin = run_case.child.stdin.take().unwrap();
+    std::thread::spawn(move || stdin.write_all(&input));
+
+    //use time and memory limit, get case result
+    let usage = match runner::wait(&mut run_case, &limit, canceled) {
+        Ok(usage) => usage,
+        Err(err) => {
+            case_result.result = MyResult::SystemError;
+            case_result.info = format!("failed to wait for program: {}", err);
+            return (case_result, 0.0);
+        }
+    };
+    case_result.memory = usage.memory as i64;
+    case_result.time = usage.cpu_time.as_micros() as i32;
+    case_result.wall_time = usage.wall_time.as_micros() as i32;
+    let output = match output.join().unwrap_or_else(|_| Err(std::io::ErrorKind::Other.into())) {
+        Ok(output) => output,
+        Err(err) => {
+            case_result.result = MyResult::SystemError;
+            case_result.info = format!("failed to read output: {}", err);
+            return (case_result, 0.0);
+        }
+    };
+    match usage.termination {
+        Termination::Canceled => {
+            case_result.result = MyResult::Skipped;
+        }
+        _ if output.len() as u64 > output_limit => {
+            case_result.result = MyResult::OutputLimitExceeded;
+        }
+        Termination::TimedOut => {
             case_result.result = MyResult::TimeLimitExceeded;
         }
-        Some(s) => {
-            if s.success() {
+        Termination::Signaled(signal) if sandbox.enabled && sandbox::is_violation(signal) => {
+            case_result.result = MyResult::SandboxViolation;
+            case_result.info = format!("killed by signal {}", signal);
+        }
+        _ if usage.is_memory_exceeded(&limit) => {
+            case_result.result = MyResult::MemoryLimitExceeded;
+        }
+        s => {
+            if s == Termination::Exited(0) || (problem.misc.ignore_exit_code && matches!(s, Termination::Exited(_))) {
 
                 //run successfully, match result
-                let out = run_case.stdout;
-                let mut output = String::new();
-                out.unwrap().read_to_string(&mut output).unwrap();
-                let ans = fs::read_to_string(&case.answer_file).unwrap();
-
                 match &problem.ty {
-                    ProblemType::Standard => {
-                        let a: Vec<&str> =
-                            output.split("\n").map(|x| x.trim()).collect();
-                        let b: Vec<&str> = ans.split("\n").map(|x| x.trim()).collect();
-                        if a == b {
-                            case_result.result = MyResult::Accepted;
-                        } else {
-                            case_result.result = MyResult::WrongAnswer;
-                        }
-                    }
-                    ProblemType::Strict => {
-                        if ans == output {
-                            case_result.result = MyResult::Accepted;
-                        } else {
-                            case_result.result = MyResult::WrongAnswer;
-                        }
-                    }
                     ProblemType::Spj => {
-                        let spj_result = special_judge(problem, case, output);
+                        let spj_result = special_judge(problem, checker, case, dir_path, &output, canceled);
                         case_result.result = spj_result.0;
                         case_result.info = spj_result.1;
+                        ratio = spj_result.2;
                     }
-                    ProblemType::DynamicRanking => {
-                        let a: Vec<&str> =
-                            output.split("\n").map(|x| x.trim()).collect();
-                        let b: Vec<&str> = ans.split("\n").map(|x| x.trim()).collect();
-                        if a == b {
-                            case_result.result = MyResult::Accepted;
-                        } else {
+                    ProblemType::Interactive => unreachable!("judged by interact()"),
+                    _ => match (String::from_utf8(output), fs::read_to_string(&case.answer_file)) {
+                        (_, Err(err)) => {
+                            case_result.result = MyResult::SystemError;
+                            case_result.info = format!("failed to read answer: {}", err);
+                        }
+                        (Err(_), _) => {
                             case_result.result = MyResult::WrongAnswer;
+                            case_result.info = "output is not valid UTF-8".to_string();
                         }
-                    }
+                        (Ok(output), Ok(ans)) => case_result.result = compare_output(problem, &output, &ans),
+                    },
                 }
                 //got result, update response
             } else {
                 case_result.result = MyResult::RuntimeError;
+                case_result.info = s.to_string();
+                if let Some((tail, dropped)) = stderr.and_then(|x| x.join().ok()) {
+                    if !tail.is_empty() {
+                        let cut = if dropped { "...\n" } else { "" };
+                        case_result.info = format!("{}\n{}{}", s, cut, String::from_utf8_lossy(&tail));
+                    }
+                }
             }
         }
     }
-    case_result
+    match case_result.result {
+        MyResult::Accepted => (case_result, 1.0),
+        MyResult::PartiallyCorrect => (case_result, ratio),
+        _ => (case_result, 0.0),
+    }
 }
 
-///Run special judge program, return it's result and info string
-fn special_judge(problem: &Problem, case: &Case, output: String) -> (MyResult, String) {
+///Judge a running program on a case of an interactive problem: the interactor of the problem talks with it
+/// through its stdin and stdout, under the time limit as well, and tells the result unless the program failed first.
+#[allow(clippy::too_many_arguments)]
+fn interact(
+    problem: &Problem,
+    case: &Case,
+    sandbox: &Sandbox,
+    dir_path: &str,
+    mut program: runner::Process,
+    limit: &Limit,
+    canceled: &Arc<AtomicBool>,
+    mut case_result: CaseResult,
+) -> (CaseResult, f64) {
+    let output_file = format!("{}/output", dir_path);
+    let interactor: Vec<String> = problem.misc.interactor.clone().unwrap_or_default().iter()
+        .map(|x| {
+            x.replace("%INPUT%", &case.input_file)
+                .replace("%OUTPUT%", &output_file)
+                .replace("%ANSWER%", &case.answer_file)
+        })
+        .collect();
+    let to_program = program.child.stdin.take().unwrap();
+    let from_program = program.child.stdout.take().unwrap();
+    let interactor_limit = Limit { time: limit.time + INTERACTOR_GRACE, cpu: Duration::ZERO, memory: 0 };
+    //wait() only sees children of its own thread, so the interactor is started and waited for in another one
+    let interactor = {
+        let canceled = canceled.clone();
+        std::thread::spawn(move || -> std::io::Result<(runner::Usage, Vec<u8>)> {
+            let mut command = Command::new(&interactor[0]);
+            command.args(&interactor[1..]).stdin(from_program).stdout(to_program).stderr(Stdio::piped());
+            let mut process = runner::spawn(&mut command, &interactor_limit, None)?;
+            //drop the pipe ends kept by command, so that each side sees the other one exit
+            drop(command);
+            let stderr = read_capped(process.child.stderr.take().unwrap(), CHECKER_INFO_LIMIT);
+            let usage = runner::wait(&mut process, &interactor_limit, &canceled)?;
+            Ok((usage, stderr.join().unwrap_or_default()))
+        })
+    };
+    let usage = runner::wait(&mut program, limit, canceled);
+    let verdict = match interactor.join() {
+        Ok(Ok((usage, message))) => match usage.termination {
+            Termination::Exited(code) => testlib_result(Some(code), &message),
+            Termination::TimedOut => (MyResult::SPJError, "interactor timed out".to_string(), 0.0),
+            Termination::Canceled => (MyResult::Skipped, String::new(), 0.0),
+            Termination::Signaled(signal) => (MyResult::SPJError, format!("interactor killed by signal {}", signal), 0.0),
+        },
+        Ok(Err(err)) => (MyResult::SPJError, format!("failed to run interactor: {}", err), 0.0),
+        Err(_) => (MyResult::SPJError, "interactor panicked".to_string(), 0.0),
+    };
+    let usage = match usage {
+        Ok(usage) => usage,
+        Err(err) => {
+            case_result.result = MyResult::SystemError;
+            case_result.info = format!("failed to wait for program: {}", err);
+            return (case_result, 0.0);
+        }
+    };
+    case_result.memory = usage.memory as i64;
+    case_result.time = usage.cpu_time.as_micros() as i32;
+    case_result.wall_time = usage.wall_time.as_micros() as i32;
+    let (result, info, ratio) = match usage.termination {
+        Termination::TimedOut => (MyResult::TimeLimitExceeded, String::new(), 0.0),
+        Termination::Canceled => (MyResult::Skipped, String::new(), 0.0),
+        Termination::Signaled(signal) if sandbox.enabled && sandbox::is_violation(signal) => {
+            (MyResult::SandboxViolation, format!("killed by signal {}", signal), 0.0)
+        }
+        _ if usage.is_memory_exceeded(limit) => (MyResult::MemoryLimitExceeded, String::new(), 0.0),
+        //a program told it's wrong may well fail afterwards, as the interactor stops talking
+        Termination::Exited(0) => verdict,
+        _ if verdict.0 != MyResult::Accepted && verdict.0 != MyResult::PartiallyCorrect => verdict,
+        s => (MyResult::RuntimeError, s.to_string(), 0.0),
+    };
+    case_result.result = result;
+    case_result.info = info;
+    (case_result, ratio)
+}
+
+///Run special judge program, or checker if the problem has one,
+/// return it's result, info string and the ratio of the case score a partially correct output earns
+fn special_judge(
+    problem: &Problem,
+    checker: Option<&[String]>,
+    case: &Case,
+    dir_path: &str,
+    output: &[u8],
+    canceled: &Arc<AtomicBool>,
+) -> (MyResult, String, f64) {
+    let output_file = format!("{}/output", dir_path);
+    if let Err(err) = fs::write(&output_file, output) {
+        return (MyResult::SystemError, format!("failed to save output: {}", err), 0.0);
+    }
+    //a checker built from source is called like testlib ones, with input, output and answer
+    let (spj, protocol) = match checker {
+        Some(checker) => {
+            let mut spj = checker.to_vec();
+            spj.extend([case.input_file.clone(), output_file, case.answer_file.clone()]);
+            (spj, CheckerProtocol::Testlib)
+        }
+        None => {
+            let spj: Vec<String> = problem.misc.special_judge.clone().unwrap().iter()
+                .map(|x| {
+                    x.replace("%INPUT%", &case.input_file)
+                        .replace("%OUTPUT%", &output_file)
+                        .replace("%ANSWER%", &case.answer_file)
+                })
+                .collect();
+            (spj, problem.misc.checker_protocol)
+        }
+    };
+    //a checker that hangs must not hold its worker, so it runs under a limit like the interactor
+    let limit = Limit { time: CHECKER_TIME_LIMIT, cpu: Duration::ZERO, memory: 0 };
+    let mut command = Command::new(&spj[0]);
+    command.args(&spj[1..]).stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    let usage = runner::spawn(&mut command, &limit, None).and_then(|mut process| {
+        let stdout = read_capped(process.child.stdout.take().unwrap(), CHECKER_INFO_LIMIT);
+        let stderr = read_capped(process.child.stderr.take().unwrap(), CHECKER_INFO_LIMIT);
+        let usage = runner::wait(&mut process, &limit, canceled)?;
+        Ok((usage, stdout.join().unwrap_or_default(), stderr.join().unwrap_or_default()))
+    });
+    let (usage, stdout, stderr) = match usage {
+        Ok(usage) => usage,
+        Err(err) => return (MyResult::SPJError, format!("failed to run special judge: {}", err), 0.0),
+    };
+    let status = match usage.termination {
+        Termination::Exited(code) => ExitStatus::from_raw(code << 8),
+        Termination::Signaled(signal) => ExitStatus::from_raw(signal),
+        Termination::TimedOut => return (MyResult::SPJError, "special judge timed out".to_string(), 0.0),
+        Termination::Canceled => return (MyResult::Skipped, String::new(), 0.0),
+    };
+    let spj_out = std::process::Output { status, stdout, stderr };
+    match protocol {
+        CheckerProtocol::ResultLine => result_line(&spj_out),
+        CheckerProtocol::Testlib => testlib_result(spj_out.status.code(), &spj_out.stderr),
+    }
+}
+
+///result of a special judge naming it in the first line of its output, info in the second,
+/// and the ratio of the case score earned in the third if it's partially correct
+fn result_line(spj_out: &std::process::Output) -> (MyResult, String, f64) {
     let case_result: MyResult;
     let mut spj_info = String::new();
-    let mut spj = problem.misc.special_judge.clone().unwrap();
-    let output_file = format!("./problem{}/output", problem.id);
-    fs::File::create(&output_file).unwrap()
-        .write(output.as_bytes()).unwrap();
-    let out_index = spj.iter().position(|x| x == "%OUTPUT%").unwrap();
-    let ans_index = spj.iter().position(|x| x == "%ANSWER%").unwrap();
-    spj[out_index] = output_file;
-    spj[ans_index] = case.answer_file.clone();
-    let spj_out = Command::new(&spj[0])
-        .args(&spj[1..])
-        .stdout(Stdio::piped())
-        .output()
-        .unwrap();
+    let mut ratio = 0.0;
     if spj_out.status.success() {
-        let spj_result = String::from_utf8(spj_out.stdout).unwrap();
+        let spj_result = String::from_utf8_lossy(&spj_out.stdout);
         match spj_result.lines().nth(0) {
             None => {
                 case_result = MyResult::SPJError
@@ -595,10 +1409,88 @@ fn special_judge(problem: &Problem, case: &Case, output: String) -> (MyResult, S
                 spj_info = s.to_string();
             }
         }
+        if let Some(s) = spj_result.lines().nth(2) {
+            ratio = parse_ratio(s);
+        }
+    } else {
+        //a special judge that failed has no say
+        case_result = MyResult::SPJError;
+        spj_info = format!("special judge {}", spj_out.status);
+    }
+    (case_result, spj_info, ratio)
+}
+
+///Result of a testlib checker by its exit code: ok, wa, pe, fail, dirt, points, unexpected eof, or partially.
+/// Points give the ratio earned first in the message, such as "points 0.5 two of four found",
+/// and partially gives it in percent, exiting with 16 + percent.
+fn testlib_result(code: Option<i32>, stderr: &[u8]) -> (MyResult, String, f64) {
+    let message = String::from_utf8_lossy(stderr);
+    let info = capped_text(message.trim().as_bytes(), CHECKER_INFO_LIMIT);
+    let (result, ratio) = match code {
+        Some(0) => (MyResult::Accepted, 1.0),
+        Some(1) | Some(4) | Some(8) => (MyResult::WrongAnswer, 0.0),
+        Some(2) => (MyResult::PresentationError, 0.0),
+        Some(7) => {
+            let points = message.trim_start().trim_start_matches("points").split_whitespace().next();
+            (MyResult::PartiallyCorrect, points.map_or(0.0, parse_ratio))
+        }
+        Some(code) if (16..=216).contains(&code) => (MyResult::PartiallyCorrect, ((code - 16) as f64 / 100.0).min(1.0)),
+        //3 is the checker failing, anything else is no testlib result at all
+        _ => (MyResult::SPJError, 0.0),
+    };
+    (result, info, ratio)
+}
+
+///a ratio of score given by a special judge, kept between 0 and 1
+fn parse_ratio(text: &str) -> f64 {
+    match text.trim().parse::<f64>() {
+        Ok(ratio) if ratio.is_finite() => ratio.clamp(0.0, 1.0),
+        _ => 0.0,
+    }
+}
+
+///Result of output against ans by the built-in comparison of the problem type. Strict output matching ans only
+/// as in standard is a presentation error.
+fn compare_output(problem: &Problem, output: &str, ans: &str) -> MyResult {
+    let lines = |text: &str| -> Vec<String> { text.split('\n').map(|x| x.trim().to_string()).collect() };
+    let sorted = |mut x: Vec<String>| {
+        x.sort_unstable();
+        x
+    };
+    let tokens = |text: &str| -> Vec<String> { text.split_whitespace().map(|x| x.to_string()).collect() };
+    let same = match problem.ty {
+        ProblemType::Standard | ProblemType::DynamicRanking => lines(output) == lines(ans),
+        ProblemType::Strict if output != ans && lines(output) == lines(ans) => return MyResult::PresentationError,
+        ProblemType::Strict => output == ans,
+        ProblemType::Float => float_match(output, ans, problem.misc.epsilon.unwrap_or(EPSILON_DEFAULT)),
+        ProblemType::Tokens => tokens(output) == tokens(ans),
+        ProblemType::CaseInsensitive => lines(&output.to_lowercase()) == lines(&ans.to_lowercase()),
+        ProblemType::UnorderedLines => {
+            let non_empty = |text| lines(text).into_iter().filter(|x| !x.is_empty()).collect();
+            sorted(non_empty(output)) == sorted(non_empty(ans))
+        }
+        ProblemType::UnorderedTokens => sorted(tokens(output)) == sorted(tokens(ans)),
+        ProblemType::Spj | ProblemType::Interactive => unreachable!("judged by a checker or interactor"),
+    };
+    if same {
+        MyResult::Accepted
     } else {
-        case_result = MyResult::Accepted;
+        MyResult::WrongAnswer
     }
-    (case_result, spj_info)
+}
+
+///Whether output has the tokens of ans, numbers being equal if their absolute or relative error is within epsilon.
+fn float_match(output: &str, ans: &str, epsilon: f64) -> bool {
+    let a: Vec<&str> = output.split_whitespace().collect();
+    let b: Vec<&str> = ans.split_whitespace().collect();
+    a.len() == b.len()
+        && a.iter().zip(b.iter()).all(|(x, y)| match (x.parse::<f64>(), y.parse::<f64>()) {
+            (Ok(x), Ok(y)) if x.is_finite() && y.is_finite() => {
+                let error = (x - y).abs();
+                error <= epsilon || error <= epsilon * y.abs()
+            }
+            _ => x == y,
+        })
 }
 
 ///Receive a filter ,a job, and user list, return whether it satisfies.
@@ -610,15 +1502,15 @@ pub fn match_job(require: &GetJob, job: &Job, user_list: &Vec<User>) -> bool {
         }
     }
     if let Some(parameter) = &require.from {
-        let from_time = DateTime::parse_from_str(&parameter, TIME_FMT).unwrap();
-        let actual_time = DateTime::parse_from_str(&job.created_time, TIME_FMT).unwrap();
+        let from_time = NaiveDateTime::parse_from_str(&parameter, TIME_FMT).unwrap();
+        let actual_time = NaiveDateTime::parse_from_str(&job.created_time, TIME_FMT).unwrap();
         if from_time >= actual_time {
             return false;
         }
     }
     if let Some(parameter) = &require.to {
-        let to_time = DateTime::parse_from_str(&parameter, TIME_FMT).unwrap();
-        let actual_time = DateTime::parse_from_str(&job.created_time, TIME_FMT).unwrap();
+        let to_time = NaiveDateTime::parse_from_str(&parameter, TIME_FMT).unwrap();
+        let actual_time = NaiveDateTime::parse_from_str(&job.created_time, TIME_FMT).unwrap();
         if to_time <= actual_time {
             return false;
         }
@@ -649,7 +1541,7 @@ pub fn match_job(require: &GetJob, job: &Job, user_list: &Vec<User>) -> bool {
         }
     }
     if let Some(parameter) = &require.user_name {
-        if parameter != &(user_list[job.submission.user_id as usize]).name {
+        if user_list.get(job.submission.user_id as usize).is_none_or(|x| parameter != &x.name) {
             return false;
         }
     }
@@ -674,22 +1566,22 @@ pub fn get_user_submissions(contest_id: i32, user: &User, job_list: &Vec<Job>) -
 }
 
 ///return score list for cases in a contest, and their index list for tie break to judge.
-pub fn get_score_list(contest: &Contest, all_jobs: &Vec<Job>, user_jobs: &Vec<Job>, rule: &RankRule, config: &Config) -> (Vec<f64>, Vec<usize>) {
+pub fn get_score_list(contest: &Contest, all_jobs: &Vec<Job>, user_jobs: &Vec<Job>, rule: &RankRule, all_problems: &[Problem]) -> (Vec<f64>, Vec<usize>) {
     let mut scores: Vec<f64> = vec![];
     let mut indexes: Vec<usize> = vec![];
     let problems: Vec<Problem>;
     if contest.id.unwrap() == 0 {
-        problems = config.problems.clone();
+        problems = all_problems.to_vec();
     } else {
         problems = contest.problem_ids.iter().map(|x| {
-            config.problems.iter().find(|y| y.id == *x).unwrap()
+            all_problems.iter().find(|y| y.id == *x).unwrap()
         }).cloned().collect();
     }
     for problem in problems.iter() {
         //get list of users that can have competitive score.
         let mut accepted_jobs: Vec<Job> = vec![];
         for job in all_jobs {
-            if job.submission.problem_id == problem.id {
+            if job.submission.problem_id == problem.id && job.state != State::Canceled {
                 if let MyResult::Accepted = &job.result {
                     accepted_jobs.push(job.clone());
                 }
@@ -699,7 +1591,8 @@ pub fn get_score_list(contest: &Contest, all_jobs: &Vec<Job>, user_jobs: &Vec<Jo
         let mut time: DateTime<FixedOffset> = chrono::DateTime::default();
         let mut index: usize = 0;
         for job_index in 0..user_jobs.len() {
-            if user_jobs[job_index].submission.problem_id == problem.id {
+            //canceled jobs don't count
+            if user_jobs[job_index].submission.problem_id == problem.id && user_jobs[job_index].state != State::Canceled {
                 let i_time: DateTime<FixedOffset> = chrono::DateTime::from_str(&user_jobs[job_index].created_time).unwrap();
                 match rule.scoring_rule {
                     ScoringRule::Latest => {
@@ -719,9 +1612,10 @@ pub fn get_score_list(contest: &Contest, all_jobs: &Vec<Job>, user_jobs: &Vec<Jo
                 // get dynamic score.
                 if let MyResult::Accepted = user_jobs[job_index].result {
                     for case_index in 0..problem.cases.len() {
-                        let min_time = accepted_jobs.iter().map(|x| x.cases[case_index + 1].time).min().unwrap();
+                        //cpu time of a quick program may well be 0
+                        let min_time = accepted_jobs.iter().map(|x| x.cases[case_index + 1].time.max(1)).min().unwrap();
                         score += problem.cases[case_index].score * problem.misc.dynamic_ranking_ratio.unwrap_or(0.0)
-                            * (min_time as f64 / user_jobs[job_index].cases[case_index + 1].time as f64);
+                            * (min_time as f64 / user_jobs[job_index].cases[case_index + 1].time.max(1) as f64);
                     }
                 }
             }
@@ -758,4 +1652,101 @@ pub fn compare_users(a: &Vec<Job>, b: &Vec<Job>, s: (f64, f64), ind: (Vec<usize>
         return a;
     }
     a_score.partial_cmp(&b_score).unwrap()
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn job_dir_is_removed_after_panic() {
+        let path = std::panic::catch_unwind(|| {
+            let dir = JobDir::create(&std::env::temp_dir().join("oj"), -1).unwrap();
+            fs::write(dir.path().join("main"), "").unwrap();
+            panic!("{}", dir.path().display());
+        })
+        .unwrap_err();
+        let path = path.downcast::<String>().unwrap();
+        assert!(!Path::new(path.as_str()).exists());
+    }
+
+    #[test]
+    fn compiler_output_is_capped() {
+        let output = read_capped(&b"error: expected `;`"[..], 5).join().unwrap();
+        assert_eq!(capped_text(&output, 5), "error\n(output truncated)");
+        assert_eq!(capped_text(b"ok", 5), "ok");
+    }
+
+    #[test]
+    fn job_dir_replaces_leftover() {
+        let root = std::env::temp_dir().join("oj");
+        let leftover = root.join("job-2");
+        fs::create_dir_all(&leftover).unwrap();
+        fs::write(leftover.join("main"), "").unwrap();
+        let dir = JobDir::create(&root, -2).unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn testlib_exit_codes_are_results() {
+        let checker = |script: &str| {
+            let output = Command::new("sh").args(["-c", script]).output().unwrap();
+            testlib_result(output.status.code(), &output.stderr)
+        };
+        assert!(checker("exit 0").0 == MyResult::Accepted);
+        assert!(checker("exit 2").0 == MyResult::PresentationError);
+        assert!(checker("exit 3").0 == MyResult::SPJError);
+        let (result, _, ratio) = checker("echo 'points 0.25 one of four found' >&2; exit 7");
+        assert!(result == MyResult::PartiallyCorrect);
+        assert_eq!(ratio, 0.25);
+        assert_eq!(checker("exit 66").2, 0.5);
+        let (result, info, _) = checker("echo 'wrong answer expected 3, found 4' >&2; exit 1");
+        assert!(result == MyResult::WrongAnswer);
+        assert_eq!(info, "wrong answer expected 3, found 4");
+        assert!(checker("kill -9 $$").0 == MyResult::SPJError);
+    }
+
+    #[test]
+    fn result_line_gives_ratio() {
+        let spj = |script: &str| result_line(&Command::new("sh").args(["-c", script]).output().unwrap());
+        let (result, info, ratio) = spj("printf 'Partially Correct\\nlonger than best\\n0.8\\n'");
+        assert!(result == MyResult::PartiallyCorrect);
+        assert_eq!(info, "longer than best");
+        assert_eq!(ratio, 0.8);
+        assert_eq!(spj("printf 'Partially Correct\\n\\n7\\n'").2, 1.0);
+        assert!(spj("echo Accepted; exit 1").0 == MyResult::SPJError);
+    }
+
+    #[test]
+    fn float_match_allows_error() {
+        assert!(float_match("3.1415927\n2e6\n", "3.14159265 2000001.5", 1e-6));
+        assert!(float_match("1.0000005 yes", "1 yes", 1e-6));
+        assert!(!float_match("1.00001 yes", "1 yes", 1e-6));
+        assert!(!float_match("1 no", "1 yes", 1e-6));
+        assert!(!float_match("1", "1 1", 1e-6));
+        assert!(!float_match("nan", "1", 1e-6));
+    }
+
+    #[test]
+    fn built_in_comparisons() {
+        let compare = |ty: &str, output: &str, ans: &str| {
+            let problem: Problem = serde_json::from_value(serde_json::json!({
+                "id": 0, "name": "p", "type": ty, "misc": {}, "cases": []
+            }))
+            .unwrap();
+            compare_output(&problem, output, ans)
+        };
+        let same = |ty: &str, output: &str, ans: &str| compare(ty, output, ans) == MyResult::Accepted;
+        assert!(same("standard", "1 2 \n3\n", "1 2\n3\n"));
+        assert!(!same("standard", "1  2\n3\n", "1 2\n3\n"));
+        assert!(same("tokens", "1  2\n\n3", "1 2 3\n"));
+        assert!(!same("tokens", "1 3 2", "1 2 3"));
+        assert!(same("case_insensitive", "YES\nno\n", "yes\nNo\n"));
+        assert!(same("unordered_lines", "b\na 1\n\n", "a 1\nb\n"));
+        assert!(!same("unordered_lines", "a\nb\nb\n", "a\na\nb\n"));
+        assert!(same("unordered_tokens", "3 1\n2", "1 2 3"));
+        assert!(!same("unordered_tokens", "1 2", "1 2 2"));
+        assert!(compare("strict", "1 2\n", "1 2\n") == MyResult::Accepted);
+        assert!(compare("strict", "1 2 \n", "1 2\n") == MyResult::PresentationError);
+        assert!(compare("strict", "1 3\n", "1 2\n") == MyResult::WrongAnswer);
+    }
+}
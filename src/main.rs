@@ -1,7 +1,7 @@
 use actix_web;
-use actix_web::rt::spawn;
 use actix_web::{
-    get, middleware::Logger, post, put, web, App, HttpResponse, HttpServer, Responder,
+    delete, get, http::header, middleware::Logger, post, put, web, App, HttpRequest, HttpResponse, HttpServer,
+    Responder,
 };
 use clap;
 use clap::Parser;
@@ -9,13 +9,25 @@ use env_logger;
 use lazy_static::lazy_static;
 use log;
 use oj;
-use oj::{compare_users, get_score_list, get_user_submissions, match_job, run_job,
-         Config, Job, PostJob, Reason, User, UserRank, Contest};
+use oj::auth::{self, Login, Role};
+use oj::storage::{SqliteStorage, Storage};
+use oj::{check_job, compare_users, get_score_list, get_user_submissions, match_job, run_job, sweep_job_dirs,
+         Config, Job, JobQueue, PostJob, Problem, Reason, Rejudge, User, UserRank, Contest};
 use std::cmp::Ordering;
 use std::fs;
 use std::ops::Deref;
-use std::sync::{Arc, Mutex};
-use chrono::{FixedOffset};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex, OnceLock};
+use actix_web::web::Bytes;
+use futures_util::stream;
+use std::collections::VecDeque;
+use tokio::sync::broadcast;
+use chrono::{FixedOffset, Utc};
 lazy_static! {
     static ref JOB_LIST: Arc<Mutex<Vec<Job>>> = Arc::new(Mutex::new(Vec::new()));
 }
@@ -25,6 +37,75 @@ lazy_static! {
 lazy_static! {
     static ref CONTEST_LIST: Arc<Mutex<Vec<Contest>>> = Arc::new(Mutex::new(vec![]));
 }
+lazy_static! {
+    static ref PROBLEM_LIST: Arc<Mutex<Vec<Problem>>> = Arc::new(Mutex::new(vec![]));
+}
+lazy_static! {
+    static ref REJUDGE_LIST: Arc<Mutex<Vec<Rejudge>>> = Arc::new(Mutex::new(vec![]));
+}
+lazy_static! {
+    static ref JOB_QUEUE: Arc<JobQueue> = Arc::new(JobQueue::default());
+}
+lazy_static! {
+    //every new version of a job, for event streams
+    static ref JOB_EVENTS: broadcast::Sender<Job> = broadcast::channel(1024).0;
+}
+lazy_static! {
+    //taken by requests changing problems for as long as a checker builds, judges don't wait for it
+    static ref PROBLEM_EDITS: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+lazy_static! {
+    //taken by a rejudge from choosing its jobs until they are queued, so that rejudges get ids of their own
+    static ref REJUDGE_POSTS: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+lazy_static! {
+    //cancel flags of jobs taken by workers, always locked after JOB_LIST
+    static ref RUNNING_JOBS: Mutex<HashMap<i32, Arc<AtomicBool>>> = Mutex::new(HashMap::new());
+}
+//largest zip of test data accepted
+const DATA_UPLOAD_LIMIT: usize = 256 << 20;
+//set once at start up
+static STORAGE: OnceLock<Box<dyn Storage>> = OnceLock::new();
+
+fn storage() -> &'static dyn Storage {
+    STORAGE.get().unwrap().as_ref()
+}
+
+///The user who sent a request, by the token in its Authorization header.
+/// None if auth is disabled, then anyone may do anything.
+fn caller(req: &HttpRequest, config: &Config) -> Result<Option<User>, oj::Error> {
+    if !config.auth.enabled {
+        return Ok(None);
+    }
+    let token = bearer_token(req).ok_or_else(auth::unauthorized)?;
+    let id = storage().token_user(token, Utc::now().timestamp())?.ok_or_else(auth::unauthorized)?;
+    match UESR_LIST.lock().unwrap().iter().find(|x| x.id == Some(id)) {
+        Some(user) => Ok(Some(user.clone())),
+        None => Err(auth::unauthorized()),
+    }
+}
+
+///caller of a request, who must have role
+fn require(req: &HttpRequest, config: &Config, role: Role) -> Result<Option<User>, oj::Error> {
+    match caller(req, config)? {
+        Some(user) if !user.role.has(role) => Err(auth::forbidden()),
+        user => Ok(user),
+    }
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers().get(header::AUTHORIZATION)?.to_str().ok()?.strip_prefix("Bearer ")
+}
+
+///A job as a caller may see it: source code is only shown to its submitter and problem setters.
+fn censor(job: Job, viewer: &Result<Option<User>, oj::Error>) -> Job {
+    match viewer {
+        Ok(None) => job,
+        Ok(Some(user)) if user.role.has(Role::ProblemSetter) || user.id == Some(job.submission.user_id) => job,
+        _ => job.without_source(),
+    }
+}
+
 #[get("/hello/{name}")]
 async fn greet(name: web::Path<String>) -> impl Responder {
     log::info!(target: "greet_handler", "Greeting {}", *name);
@@ -34,17 +115,62 @@ async fn greet(name: web::Path<String>) -> impl Responder {
 // DO NOT REMOVE: used in automatic testing
 #[post("/internal/exit")]
 #[allow(unreachable_code)]
-async fn exit() -> impl Responder {
-    save_data();
+async fn exit(req: HttpRequest, config: web::Data<Config>) -> impl Responder {
+    if let Err(err) = require(&req, &config, Role::Admin) {
+        return err.to_response();
+    }
     log::info!("Shutdown as requested");
     std::process::exit(0);
-    format!("Exited")
+    HttpResponse::Ok().body("Exited")
+}
+
+#[post("/login")]
+async fn login(body: web::Json<Login>, config: web::Data<Config>) -> impl Responder {
+    let user = UESR_LIST.lock().unwrap().iter().find(|x| x.name == body.name).cloned();
+    //users without a password can't log in
+    let hash = match &user {
+        Some(user) => match storage().password_hash(user.id.unwrap()) {
+            Ok(hash) => hash,
+            Err(err) => return err.to_response(),
+        },
+        None => None,
+    };
+    //a password is checked even without a user, which a quicker answer would give away
+    let verified = auth::verify_login(&body.password, hash.as_deref());
+    let user = match user {
+        Some(user) if verified => user,
+        _ => return auth::unauthorized().to_response(),
+    };
+    let token = auth::new_token();
+    let expires = Utc::now().timestamp() + config.auth.token_lifetime;
+    if let Err(err) = storage().save_token(&token, user.id.unwrap(), expires) {
+        return err.to_response();
+    }
+    HttpResponse::Ok().json(serde_json::json!({ "token": token, "user": user }))
+}
+
+#[post("/logout")]
+async fn logout(req: HttpRequest) -> impl Responder {
+    match bearer_token(&req) {
+        Some(token) => match storage().delete_token(token) {
+            Ok(()) => HttpResponse::Ok().finish(),
+            Err(err) => err.to_response(),
+        },
+        None => auth::unauthorized().to_response(),
+    }
 }
 
 #[post("/jobs")]
-async fn post_jobs(body: web::Json<PostJob>, config: web::Data<Config>) -> impl Responder {
+async fn post_jobs(req: HttpRequest, body: web::Json<PostJob>, config: web::Data<Config>) -> impl Responder {
+    //only admins submit for others
+    match require(&req, &config, Role::Contestant) {
+        Ok(Some(user)) if !user.role.has(Role::Admin) && user.id != Some(body.user_id) => {
+            return auth::forbidden().to_response();
+        }
+        Err(err) => return err.to_response(),
+        _ => {}
+    }
     let mut lock = JOB_LIST.lock().unwrap();
-    let job_list = lock.clone();
     let contest_list = CONTEST_LIST.lock().unwrap().to_vec();
     let id = lock.len();
     //create a job
@@ -57,14 +183,17 @@ async fn post_jobs(body: web::Json<PostJob>, config: web::Data<Config>) -> impl
             message: "User id not found".to_string(),
         })
     } else {
-        let result = web::block(move || {
-            run_job(&mut job, &config, &contest_list, job_list)
-        }).await;
-
-        match result.unwrap() {
-            Ok(job) => {
-                //push modified job
+        let problems = PROBLEM_LIST.lock().unwrap().to_vec();
+        match check_job(&job, &config, &problems, &contest_list, &lock) {
+            Ok(problem) => {
+                //push queueing job, and leave it to judge workers
+                job.reset(problem.cases.len());
+                if let Err(err) = storage().save_job(&job) {
+                    return err.to_response();
+                }
                 lock.push(job.clone());
+                JOB_QUEUE.push(job.id);
+                JOB_EVENTS.send(job.clone()).ok();
                 HttpResponse::Ok().json(job)
             }
             Err(err) => err.to_response()
@@ -73,33 +202,125 @@ async fn post_jobs(body: web::Json<PostJob>, config: web::Data<Config>) -> impl
 }
 
 #[get("/jobs")]
-async fn get_jobs(query: web::Query<oj::GetJob>) -> impl Responder {
-    let mut return_list: Vec<Job> = vec![];
-    for i in &*JOB_LIST.lock().unwrap() {
-        if match_job(&query, i, UESR_LIST.lock().unwrap().as_ref()) {
-            return_list.push(i.clone());
+async fn get_jobs(req: HttpRequest, query: web::Query<oj::GetJob>, config: web::Data<Config>) -> impl Responder {
+    let viewer = caller(&req, &config);
+    //the page itself keeps the shape of a plain list, the count of all matching jobs goes to a header
+    match storage().query_jobs(&query) {
+        Ok((return_list, total)) => HttpResponse::Ok()
+            .insert_header(("X-Total-Count", total.to_string()))
+            .json(return_list.into_iter().map(|x| censor(x, &viewer)).collect::<Vec<_>>()),
+        Err(err) => err.to_response(),
+    }
+}
+
+///Stream a job and then each new version of it as server-sent events, until it's finished or canceled.
+#[get("/jobs/{job_id}/events")]
+async fn get_job_events(req: HttpRequest, job_id: web::Path<i32>, config: web::Data<Config>) -> impl Responder {
+    let id: i32 = job_id.into_inner();
+    let viewer = caller(&req, &config);
+    //subscribe before looking at the job, so that no change in between is missed
+    let receiver = JOB_EVENTS.subscribe();
+    match JOB_LIST.lock().unwrap().iter().find(|x| x.id == id).cloned() {
+        Some(job) => {
+            let first = vec![censor(job, &viewer)];
+            let view = {
+                let viewer = viewer.clone();
+                move |x: &Job| (x.id == id).then(|| censor(x.clone(), &viewer))
+            };
+            let current = move || JOB_LIST.lock().unwrap().iter().find(|x| x.id == id).map(|x| censor(x.clone(), &viewer));
+            event_stream(receiver, first, Box::new(view), Some(Box::new(current)))
         }
+        None => HttpResponse::NotFound().json(oj::Error {
+            reason: Reason::ErrNotFound,
+            code: 3,
+            message: format!("Job {} not found.", id),
+        }),
+    }
+}
+
+///Stream new versions of all jobs passing a filter of get_jobs as server-sent events, for a live submission feed.
+#[get("/jobs/events")]
+async fn get_jobs_events(req: HttpRequest, query: web::Query<oj::GetJob>, config: web::Data<Config>) -> impl Responder {
+    let query = query.into_inner();
+    //the filter is used once the stream is open, where it can't fail anymore
+    if let Err(err) = query.check_times() {
+        return err.to_response();
     }
-    return HttpResponse::Ok().json(return_list);
+    let viewer = caller(&req, &config);
+    let view = move |job: &Job| {
+        match_job(&query, job, UESR_LIST.lock().unwrap().as_ref()).then(|| censor(job.clone(), &viewer))
+    };
+    event_stream(JOB_EVENTS.subscribe(), vec![], Box::new(view), None)
+}
+
+///a job as a client is shown it in an event stream, None if it's not for the client
+type JobView = Box<dyn Fn(&Job) -> Option<Job>>;
+
+///the current version of the single job of an event stream, as the client is shown it
+type CurrentJob = Box<dyn Fn() -> Option<Job>>;
+
+///Respond with an event stream of first, then jobs from receiver as view shows them, skipping those it hides.
+/// A stream of a single job has its current version, read again when the receiver missed some,
+/// and ends after the job is no longer pending.
+fn event_stream(
+    receiver: broadcast::Receiver<Job>,
+    first: Vec<Job>,
+    view: JobView,
+    current: Option<CurrentJob>,
+) -> HttpResponse {
+    let events = stream::unfold(
+        (receiver, VecDeque::from(first), view, current, false),
+        move |(mut receiver, mut pending, view, current, done)| async move {
+            if done {
+                return None;
+            }
+            let job = match pending.pop_front() {
+                Some(job) => job,
+                None => loop {
+                    match receiver.recv().await {
+                        Ok(job) => match view(&job) {
+                            Some(job) => break job,
+                            None => continue,
+                        },
+                        //a slow client misses some versions, later ones are still complete,
+                        //but the last version of a single job may be among them
+                        Err(broadcast::error::RecvError::Lagged(_)) => match current.as_ref().and_then(|x| x()) {
+                            Some(job) => break job,
+                            None => continue,
+                        },
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    }
+                },
+            };
+            let event = format!("event: job\ndata: {}\n\n", serde_json::to_string(&job).unwrap());
+            let done = current.is_some() && !job.is_pending();
+            Some((Ok::<_, actix_web::Error>(Bytes::from(event)), (receiver, pending, view, current, done)))
+        },
+    );
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events)
 }
 
 #[get("/jobs/{job_id}")]
-async fn get_job(job_id: web::Path<i32>) -> impl Responder {
+async fn get_job(req: HttpRequest, job_id: web::Path<i32>, config: web::Data<Config>) -> impl Responder {
     let id: i32 = job_id.into_inner();
     let job = JOB_LIST.lock().unwrap().iter().find(|x| x.id == id).cloned();
     return match job {
-        Some(a) => HttpResponse::Ok().json(a),
+        Some(a) => HttpResponse::Ok().json(censor(a, &caller(&req, &config))),
         None => HttpResponse::NotFound()
             .json("{ reason=ERR_NOT_FOUND, code=3, message=\"Job 123456 not found.\"}"),
     };
 }
 
 #[put("/jobs/{job_id}")]
-async fn put_job(job_id: web::Path<i32>, config: web::Data<Config>) -> impl Responder {
-    let contest_list = CONTEST_LIST.lock().unwrap().to_vec();
+async fn put_job(req: HttpRequest, job_id: web::Path<i32>, config: web::Data<Config>) -> impl Responder {
+    if let Err(err) = require(&req, &config, Role::Admin) {
+        return err.to_response();
+    }
     let mut lock = JOB_LIST.lock().unwrap();
     let id: i32 = job_id.into_inner();
-    let job_list = lock.clone();
     let job = lock.iter_mut().find(|x| x.id == id);
     if let None = job {
         return HttpResponse::NotFound().json(oj::Error {
@@ -108,15 +329,152 @@ async fn put_job(job_id: web::Path<i32>, config: web::Data<Config>) -> impl Resp
             message: "Job 123456 not found.".to_string(),
         });
     }
-    let mut job = job.unwrap();
-    match run_job(&mut job, &config, &contest_list, job_list) {
-        Ok(job_response) => {
-            HttpResponse::Ok().json(job_response)
+    let job = job.unwrap();
+    //a job can't be rejudged before its last judging ends
+    if job.is_pending() || RUNNING_JOBS.lock().unwrap().contains_key(&id) {
+        return HttpResponse::BadRequest().json(oj::Error {
+            reason: Reason::ErrInvalidState,
+            code: 2,
+            message: format!("Job {} not finished.", id),
+        });
+    }
+    match PROBLEM_LIST.lock().unwrap().iter().find(|x| x.id == job.submission.problem_id) {
+        None => {
+            HttpResponse::NotFound().json(oj::Error {
+                reason: Reason::ErrNotFound,
+                code: 3,
+                message: format!("problem{} not found", job.submission.problem_id),
+            })
+        }
+        Some(problem) => {
+            job.reset(problem.cases.len());
+            if let Err(err) = storage().save_job(job) {
+                return err.to_response();
+            }
+            JOB_QUEUE.push(job.id);
+            JOB_EVENTS.send(job.clone()).ok();
+            HttpResponse::Ok().json(job.clone())
+        }
+    }
+}
+
+///Rejudge every finished job passing a filter of get_jobs, such as all jobs of a problem after its data is fixed.
+/// The verdicts before are kept with the rejudge, to be compared with the new ones by get_rejudge.
+#[post("/jobs/rejudge")]
+async fn post_rejudge(req: HttpRequest, body: web::Json<oj::GetJob>, config: web::Data<Config>) -> impl Responder {
+    if let Err(err) = require(&req, &config, Role::Admin) {
+        return err.to_response();
+    }
+    //every matching job, not a page of them
+    let mut filter = body.into_inner();
+    filter.page = None;
+    filter.per_page = None;
+    let ids: Vec<i32> = match storage().query_jobs(&filter) {
+        Ok((jobs, _)) => jobs.iter().map(|x| x.id).collect(),
+        Err(err) => return err.to_response(),
+    };
+    let _rejudging = REJUDGE_POSTS.lock().await;
+    let problems = PROBLEM_LIST.lock().unwrap().clone();
+    let mut rejudge = Rejudge::new(REJUDGE_LIST.lock().unwrap().iter().map(|x| x.id + 1).max().unwrap_or(0), filter);
+    //jobs are reset in copies, saved together with their verdicts before any of them is judged again
+    let mut resets = Vec::new();
+    {
+        let job_list = JOB_LIST.lock().unwrap();
+        let running = RUNNING_JOBS.lock().unwrap();
+        for job in job_list.iter().filter(|x| ids.contains(&x.id)) {
+            let problem = problems.iter().find(|x| x.id == job.submission.problem_id);
+            match problem {
+                Some(problem) if !job.is_pending() && !running.contains_key(&job.id) => {
+                    rejudge.add(job);
+                    let mut reset = job.clone();
+                    reset.reset(problem.cases.len());
+                    resets.push(reset);
+                }
+                _ => rejudge.skip(job),
+            }
+        }
+    }
+    let (saving, saved_resets) = (rejudge.clone(), resets.clone());
+    let saved = web::block(move || storage().save_rejudge_with(&saving, &saved_resets)).await.unwrap_or_else(|err| {
+        Err(oj::Error {
+            reason: Reason::ErrInternal,
+            code: 6,
+            message: format!("failed to save rejudge: {}", err),
+        })
+    });
+    if let Err(err) = saved {
+        return err.to_response();
+    }
+    let progress = {
+        let mut job_list = JOB_LIST.lock().unwrap();
+        let running = RUNNING_JOBS.lock().unwrap();
+        for reset in resets {
+            //a job rejudged on its own meanwhile is queued already
+            let job = job_list.iter_mut().find(|x| x.id == reset.id && !x.is_pending() && !running.contains_key(&x.id));
+            if let Some(job) = job {
+                *job = reset;
+                JOB_QUEUE.push(job.id);
+                JOB_EVENTS.send(job.clone()).ok();
+            }
         }
-        Err(err) => {
-            err.to_response()
+        rejudge.progress(&job_list)
+    };
+    REJUDGE_LIST.lock().unwrap().push(rejudge);
+    HttpResponse::Ok().json(progress)
+}
+
+///How far a rejudge got, with the verdict of each of its jobs before and now.
+#[get("/jobs/rejudge/{id}")]
+async fn get_rejudge(req: HttpRequest, id: web::Path<i32>, config: web::Data<Config>) -> impl Responder {
+    if let Err(err) = require(&req, &config, Role::Admin) {
+        return err.to_response();
+    }
+    let id = id.into_inner();
+    match REJUDGE_LIST.lock().unwrap().iter().find(|x| x.id == id) {
+        Some(rejudge) => HttpResponse::Ok().json(rejudge.progress(&JOB_LIST.lock().unwrap())),
+        None => HttpResponse::NotFound().json(oj::Error {
+            reason: Reason::ErrNotFound,
+            code: 3,
+            message: format!("Rejudge {} not found.", id),
+        }),
+    }
+}
+
+#[delete("/jobs/{job_id}")]
+async fn delete_job(req: HttpRequest, job_id: web::Path<i32>, config: web::Data<Config>) -> impl Responder {
+    if let Err(err) = require(&req, &config, Role::Admin) {
+        return err.to_response();
+    }
+    let mut lock = JOB_LIST.lock().unwrap();
+    let id: i32 = job_id.into_inner();
+    let job = match lock.iter_mut().find(|x| x.id == id) {
+        Some(job) => job,
+        None => {
+            return HttpResponse::NotFound().json(oj::Error {
+                reason: Reason::ErrNotFound,
+                code: 3,
+                message: format!("Job {} not found.", id),
+            });
         }
+    };
+    if !job.is_pending() {
+        return HttpResponse::BadRequest().json(oj::Error {
+            reason: Reason::ErrInvalidState,
+            code: 2,
+            message: format!("Job {} not queueing or running.", id),
+        });
     }
+    //a queueing job leaves the queue, a running one is killed by its worker
+    JOB_QUEUE.remove(id);
+    if let Some(canceled) = RUNNING_JOBS.lock().unwrap().get(&id) {
+        canceled.store(true, AtomicOrdering::SeqCst);
+    }
+    job.cancel();
+    if let Err(err) = storage().save_job(job) {
+        return err.to_response();
+    }
+    JOB_EVENTS.send(job.clone()).ok();
+    HttpResponse::Ok().json(job.clone())
 }
 
 #[get("/users")]
@@ -125,48 +483,80 @@ async fn get_users() -> impl Responder {
 }
 
 #[post("/users")]
-async fn post_users(user: web::Json<User>) -> impl Responder {
+async fn post_users(req: HttpRequest, user: web::Json<User>, config: web::Data<Config>) -> impl Responder {
     let mut user = user.deref().clone();
-    let mut is_name_in = false;
-    let mut is_id_in = false;
-    for i in UESR_LIST.lock().unwrap().iter_mut() {
-        //update name
-        if user.id == i.id {
-            i.name = user.name.clone();
-            is_id_in = true;
-            break;
-        }
-        if user.name == i.name {
-            is_name_in = true;
-        }
+    //anyone may sign up as a contestant, only admins give roles and users only change themselves
+    let is_admin = match caller(&req, &config) {
+        Ok(None) => true,
+        Ok(Some(caller)) if caller.role.has(Role::Admin) => true,
+        Ok(Some(caller)) if user.id.is_some() && caller.id != user.id => return auth::forbidden().to_response(),
+        Ok(Some(_)) => false,
+        Err(err) if user.id.is_some() => return err.to_response(),
+        Err(_) => false,
+    };
+    if config.auth.enabled && user.id.is_none() && user.password.is_none() {
+        return HttpResponse::BadRequest().json(oj::Error {
+            reason: Reason::ErrInvalidArgument,
+            code: 1,
+            message: "Password required.".to_string(),
+        });
     }
-    return if is_name_in {
-        //name in, error directly
-        HttpResponse::BadRequest().json(oj::Error {
+    let hash = match user.password.take().map(|x| auth::hash_password(&x)).transpose() {
+        Ok(hash) => hash,
+        Err(err) => return err.to_response(),
+    };
+    //the database is written first, so that a failure leaves the lists as they were
+    let mut user_list = UESR_LIST.lock().unwrap();
+    if user_list.iter().any(|x| x.name == user.name && x.id != user.id) {
+        return HttpResponse::BadRequest().json(oj::Error {
             reason: Reason::ErrInvalidArgument,
             code: 1,
             message: format!("User name '{}' already exists.", user.name),
-        })
-    } else if user.id.is_none() {
-        //name not corrupt, and didn't appoint id
-        user.id = Some(UESR_LIST.lock().unwrap().len() as i32);
-        UESR_LIST.lock().unwrap().push(user.clone());
-        CONTEST_LIST.lock().unwrap()[0].user_ids.push(user.id.unwrap());
-        HttpResponse::Ok().json(user)
-    } else if !is_id_in {
-        //appointed id doesn't exist
-        HttpResponse::NotFound().json(oj::Error {
-            reason: Reason::ErrNotFound,
-            code: 3,
-            message: format!("User {} already exists.", user.id.unwrap()),
-        })
-    } else {
-        HttpResponse::Ok().json(user)
-    };
+        });
+    }
+    match user.id {
+        None => {
+            if !is_admin {
+                user.role = Role::Contestant;
+            }
+            user.id = Some(user_list.len() as i32);
+            let mut contest_list = CONTEST_LIST.lock().unwrap();
+            //every user is in contest 0
+            let mut contest = contest_list[0].clone();
+            contest.user_ids.push(user.id.unwrap());
+            if let Err(err) = storage().save_user_with(&user, hash.as_deref(), std::slice::from_ref(&contest)) {
+                return err.to_response();
+            }
+            user_list.push(user.clone());
+            contest_list[0] = contest;
+            HttpResponse::Ok().json(user)
+        }
+        Some(id) => {
+            let Some(old) = user_list.iter_mut().find(|x| x.id == Some(id)) else {
+                return HttpResponse::NotFound().json(oj::Error {
+                    reason: Reason::ErrNotFound,
+                    code: 3,
+                    message: format!("User {} already exists.", id),
+                });
+            };
+            //root stays an admin
+            if !is_admin || id == 0 {
+                user.role = old.role;
+            }
+            if let Err(err) = storage().save_user_with(&user, hash.as_deref(), &[]) {
+                return err.to_response();
+            }
+            *old = user.clone();
+            HttpResponse::Ok().json(user)
+        }
+    }
 }
 
 #[post("/contests")]
-async fn post_contest(body: web::Json<Contest>, config: web::Data<Config>) -> impl Responder {
+async fn post_contest(req: HttpRequest, body: web::Json<Contest>, config: web::Data<Config>) -> impl Responder {
+    if let Err(err) = require(&req, &config, Role::Admin) {
+        return err.to_response();
+    }
     let user_list = UESR_LIST.lock().unwrap().to_vec();
     for user_id in &body.user_ids {
         if user_list.iter().map(|x| x.id).position(|x| x.unwrap() == *user_id).is_none() {
@@ -177,8 +567,9 @@ async fn post_contest(body: web::Json<Contest>, config: web::Data<Config>) -> im
             });
         }
     }
+    let problems = PROBLEM_LIST.lock().unwrap().to_vec();
     for problem_id in &body.problem_ids {
-        if config.problems.iter().map(|x| x.id).position(|x| x == *problem_id).is_none() {
+        if problems.iter().map(|x| x.id).position(|x| x == *problem_id).is_none() {
             return HttpResponse::NotFound().json(oj::Error {
                 reason: Reason::ErrNotFound,
                 code: 3,
@@ -190,6 +581,9 @@ async fn post_contest(body: web::Json<Contest>, config: web::Data<Config>) -> im
     let mut contest_list = CONTEST_LIST.lock().unwrap();
     return if contest.id.is_none() {
         contest.id = Some(contest_list.len() as i32);
+        if let Err(err) = storage().save_contest(&contest) {
+            return err.to_response();
+        }
         contest_list.push(contest.clone());
         HttpResponse::Ok().json(contest)
     } else {
@@ -202,6 +596,9 @@ async fn post_contest(body: web::Json<Contest>, config: web::Data<Config>) -> im
                 })
             }
             Some(index) => {
+                if let Err(err) = storage().save_contest(&contest) {
+                    return err.to_response();
+                }
                 contest_list[index] = contest.clone();
                 HttpResponse::Ok().json(contest)
             }
@@ -247,7 +644,6 @@ async fn get_contest(contest_id: web::Path<i32>) -> impl Responder {
 async fn get_rank_list(
     contest_id: web::Path<i32>,
     rule: web::Query<oj::RankRule>,
-    config: web::Data<Config>,
 ) -> impl Responder {
     //get useful data
     let contest_id = contest_id.into_inner();
@@ -268,14 +664,15 @@ async fn get_rank_list(
     let mut user_list: Vec<User> = user_list.iter().filter(|x| contest.user_ids.contains(&x.id.unwrap())).cloned().collect();
     let rule = rule.deref();
     let job_list = JOB_LIST.lock().unwrap().deref().to_vec();
+    let problems = PROBLEM_LIST.lock().unwrap().to_vec();
 
     //sort users by scoring rule at first, and default use id as tie breaker
     user_list.sort_by(|a, b| {
         let a_list = get_user_submissions(contest_id, a, &job_list);
         let b_list = get_user_submissions(contest_id, b, &job_list);
-        let a_tuple = get_score_list(&contest, &job_list, &a_list, rule, config.deref());
+        let a_tuple = get_score_list(&contest, &job_list, &a_list, rule, &problems);
         let a_score: f64 = a_tuple.0.iter().sum();
-        let b_tuple = get_score_list(&contest, &job_list, &b_list, rule, config.deref());
+        let b_tuple = get_score_list(&contest, &job_list, &b_list, rule, &problems);
         let b_score: f64 = b_tuple.0.iter().sum();
         let order = compare_users(
             &b_list,
@@ -293,7 +690,7 @@ async fn get_rank_list(
 
     let mut rank: Vec<UserRank> = vec![];
     let list = get_user_submissions(contest_id, &user_list[0], &job_list);
-    let scores = get_score_list(&contest, &job_list, &list, rule, config.deref()).0;
+    let scores = get_score_list(&contest, &job_list, &list, rule, &problems).0;
     rank.push(UserRank {
         user: user_list[0].clone(),
         rank: 1,
@@ -302,13 +699,13 @@ async fn get_rank_list(
     for i in 1..user_list.len() {
         let former_list = get_user_submissions(contest_id, &user_list[i - 1], &job_list);
         let now_list = get_user_submissions(contest_id, &user_list[i], &job_list);
-        let f = get_score_list(&contest, &job_list, &former_list, rule, config.deref());
+        let f = get_score_list(&contest, &job_list, &former_list, rule, &problems);
         let f_score: f64 = f.0.iter().sum();
         let f_index = f.1;
-        let n = get_score_list(&contest, &job_list, &now_list, rule, config.deref());
+        let n = get_score_list(&contest, &job_list, &now_list, rule, &problems);
         let n_score: f64 = n.0.iter().sum();
         let n_index = n.1;
-        let scores = get_score_list(&contest, &job_list, &now_list, rule, config.deref()).0;
+        let scores = get_score_list(&contest, &job_list, &now_list, rule, &problems).0;
 
         if Ordering::Equal == compare_users(
             &former_list,
@@ -333,17 +730,192 @@ async fn get_rank_list(
     HttpResponse::Ok().json(rank)
 }
 
+#[get("/problems")]
+async fn get_problems() -> impl Responder {
+    HttpResponse::Ok().json(PROBLEM_LIST.lock().unwrap().to_vec())
+}
+
+#[get("/problems/{problem_id}")]
+async fn get_problem(problem_id: web::Path<i32>) -> impl Responder {
+    let id = problem_id.into_inner();
+    match PROBLEM_LIST.lock().unwrap().iter().find(|x| x.id == id) {
+        Some(problem) => HttpResponse::Ok().json(problem),
+        None => problem_not_found(id),
+    }
+}
+
+fn problem_not_found(id: i32) -> HttpResponse {
+    HttpResponse::NotFound().json(oj::Error {
+        reason: Reason::ErrNotFound,
+        code: 3,
+        message: format!("Problem {} not found.", id),
+    })
+}
+
+#[post("/problems")]
+async fn post_problem(req: HttpRequest, body: web::Json<Problem>, config: web::Data<Config>) -> impl Responder {
+    let is_admin = match require(&req, &config, Role::ProblemSetter) {
+        Ok(user) => user.is_none_or(|x| x.role.has(Role::Admin)),
+        Err(err) => return err.to_response(),
+    };
+    let mut problem = body.into_inner();
+    let _editing = PROBLEM_EDITS.lock().await;
+    //ids of deleted problems are not given again, old jobs still name them
+    let judged = JOB_LIST.lock().unwrap().iter().map(|x| x.submission.problem_id + 1).max();
+    problem.id = PROBLEM_LIST.lock().unwrap().iter().map(|x| x.id + 1).chain(judged).max().unwrap_or(0);
+    let data = Path::new(&config.server.problem_data).join(problem.id.to_string());
+    let checked = problem
+        .check()
+        .and_then(|_| if is_admin { Ok(()) } else { problem.check_setter(None, &data) });
+    if let Err(err) = checked {
+        return err.to_response();
+    }
+    if let Err(err) = compile_checker(&problem, &config).await.and_then(|_| storage().save_problem(&problem)) {
+        return err.to_response();
+    }
+    PROBLEM_LIST.lock().unwrap().push(problem.clone());
+    HttpResponse::Ok().json(problem)
+}
+
+#[put("/problems/{problem_id}")]
+async fn put_problem(
+    req: HttpRequest,
+    problem_id: web::Path<i32>,
+    body: web::Json<Problem>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    let is_admin = match require(&req, &config, Role::ProblemSetter) {
+        Ok(user) => user.is_none_or(|x| x.role.has(Role::Admin)),
+        Err(err) => return err.to_response(),
+    };
+    let mut problem = body.into_inner();
+    problem.id = problem_id.into_inner();
+    let _editing = PROBLEM_EDITS.lock().await;
+    let old = match PROBLEM_LIST.lock().unwrap().iter().find(|x| x.id == problem.id) {
+        Some(old) => old.clone(),
+        None => return problem_not_found(problem.id),
+    };
+    let data = Path::new(&config.server.problem_data).join(problem.id.to_string());
+    let checked = problem
+        .check()
+        .and_then(|_| if is_admin { Ok(()) } else { problem.check_setter(Some(&old), &data) });
+    if let Err(err) = checked {
+        return err.to_response();
+    }
+    if let Err(err) = compile_checker(&problem, &config).await.and_then(|_| storage().save_problem(&problem)) {
+        return err.to_response();
+    }
+    let mut problem_list = PROBLEM_LIST.lock().unwrap();
+    if let Some(old) = problem_list.iter_mut().find(|x| x.id == problem.id) {
+        *old = problem.clone();
+    }
+    HttpResponse::Ok().json(problem)
+}
+
+///Build the checker of a problem aside on a blocking thread, so that neither this worker nor the judges wait for it.
+async fn compile_checker(problem: &Problem, config: &web::Data<Config>) -> Result<(), oj::Error> {
+    let (problem, config) = (problem.clone(), config.clone());
+    web::block(move || oj::problem::compile_checker(&problem, &config)).await.unwrap_or_else(|err| {
+        Err(oj::Error {
+            reason: Reason::ErrInternal,
+            code: 6,
+            message: format!("failed to build checker: {}", err),
+        })
+    })
+}
+
+///Delete a problem and its test data, unless a contest has it or a job of it is still to be judged.
+#[delete("/problems/{problem_id}")]
+async fn delete_problem(req: HttpRequest, problem_id: web::Path<i32>, config: web::Data<Config>) -> impl Responder {
+    if let Err(err) = require(&req, &config, Role::ProblemSetter) {
+        return err.to_response();
+    }
+    let id = problem_id.into_inner();
+    let _editing = PROBLEM_EDITS.lock().await;
+    let job_list = JOB_LIST.lock().unwrap();
+    let contest_list = CONTEST_LIST.lock().unwrap();
+    let mut problem_list = PROBLEM_LIST.lock().unwrap();
+    let index = match problem_list.iter().position(|x| x.id == id) {
+        Some(index) => index,
+        None => return problem_not_found(id),
+    };
+    let in_contest = contest_list.iter().any(|x| x.problem_ids.contains(&id));
+    let judging = job_list.iter().any(|x| x.submission.problem_id == id && x.is_pending());
+    if in_contest || judging {
+        return HttpResponse::BadRequest().json(oj::Error {
+            reason: Reason::ErrInvalidState,
+            code: 2,
+            message: format!("Problem {} is in a contest or being judged.", id),
+        });
+    }
+    if let Err(err) = storage().delete_problem(id) {
+        return err.to_response();
+    }
+    let data = Path::new(&config.server.problem_data).join(id.to_string());
+    if data.exists() {
+        if let Err(err) = fs::remove_dir_all(&data) {
+            log::warn!("Failed to remove test data of problem {}: {}", id, err);
+        }
+    }
+    HttpResponse::Ok().json(problem_list.remove(index))
+}
+
+///Upload test data of a problem as a zip archive, replacing the old data.
+/// Respond with the paths of its files, for input_file and answer_file of cases.
+#[post("/problems/{problem_id}/data")]
+async fn post_problem_data(
+    req: HttpRequest,
+    problem_id: web::Path<i32>,
+    body: web::Bytes,
+    config: web::Data<Config>,
+) -> impl Responder {
+    let is_admin = match require(&req, &config, Role::ProblemSetter) {
+        Ok(user) => user.is_none_or(|x| x.role.has(Role::Admin)),
+        Err(err) => return err.to_response(),
+    };
+    let id = problem_id.into_inner();
+    let _editing = PROBLEM_EDITS.lock().await;
+    let problem = match PROBLEM_LIST.lock().unwrap().iter().find(|x| x.id == id) {
+        Some(problem) => problem.clone(),
+        None => return problem_not_found(id),
+    };
+    let dir = Path::new(&config.server.problem_data).join(id.to_string());
+    //a checker built from the data, or a header it includes, would run whatever the upload has
+    if !is_admin && problem.has_checker_in(&dir) {
+        return auth::forbidden().to_response();
+    }
+    if let Err(err) = fs::create_dir_all(&config.server.problem_data) {
+        log::error!("Failed to create {}: {}", config.server.problem_data, err);
+    }
+    let files = match oj::problem::unpack(&body, &dir) {
+        Ok(files) => files,
+        Err(err) => return err.to_response(),
+    };
+    //the checker may come with the data
+    if let Err(err) = compile_checker(&problem, &config).await {
+        return err.to_response();
+    }
+    HttpResponse::Ok().json(files.iter().map(|x| x.display().to_string()).collect::<Vec<_>>())
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let args = oj::Args::parse();
     let path = args.config;
     let is_flush = args.flush_data;
     let config: Config = serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+    let database = SqliteStorage::open(&config.server.database).unwrap();
     if is_flush {
-        UESR_LIST.lock().unwrap().push(User {
+        database.clear().unwrap();
+        let root = User {
             id: Some(0),
             name: "root".to_string(),
-        });
+            role: Role::Admin,
+            password: None,
+        };
+        database.save_user(&root).unwrap();
+        UESR_LIST.lock().unwrap().push(root);
         CONTEST_LIST.lock().unwrap().push(
             Contest {
                 id: Some(0),
@@ -361,65 +933,175 @@ async fn main() -> std::io::Result<()> {
                 submission_limit: 0,
             }
         );
+        database.save_contest(&CONTEST_LIST.lock().unwrap()[0]).unwrap();
     } else {
-        read_data();
+        //snapshots of older versions are moved into the database
+        database.import_json(Path::new(".")).unwrap();
+        *JOB_LIST.lock().unwrap() = database.load_jobs().unwrap();
+        *UESR_LIST.lock().unwrap() = database.load_users().unwrap();
+        *CONTEST_LIST.lock().unwrap() = database.load_contests().unwrap();
+        *REJUDGE_LIST.lock().unwrap() = database.load_rejudges().unwrap();
+        //jobs interrupted by last shutdown are judged again
+        for job in JOB_LIST.lock().unwrap().iter().filter(|x| x.is_pending()) {
+            JOB_QUEUE.push(job.id);
+        }
+    }
+    //root is always an admin, users of older versions had no roles
+    if let Some(root) = UESR_LIST.lock().unwrap().iter_mut().find(|x| x.id == Some(0)) {
+        root.role = Role::Admin;
+        database.save_user(root).unwrap();
     }
-    spawn(async {
-        let mut interval = actix_web::rt::time::interval(std::time::Duration::from_micros(500000));
-        loop {
-            interval.tick().await;
-            save_data();
+    if let Some(password) = &config.auth.root_password {
+        database.set_password(0, &auth::hash_password(password).unwrap()).unwrap();
+    }
+    //problems of config missing from the database are put there, they're changed by /problems afterwards
+    let mut problems = database.load_problems().unwrap();
+    for problem in &config.problems {
+        if !problems.iter().any(|x| x.id == problem.id) {
+            database.save_problem(problem).unwrap();
+            problems.push(problem.clone());
         }
-    });
-    // save_task.
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
-    HttpServer::new(move || {
+    }
+    //packages are the files problems are edited in, so they replace what the database has
+    let mut package_ids = vec![];
+    for path in &config.packages {
+        let package = oj::problem::load_package(Path::new(path))
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err.message))?;
+        if config.problems.iter().any(|x| x.id == package.id) || package_ids.contains(&package.id) {
+            let message = format!("{}: problem {} is given twice", path, package.id);
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message));
+        }
+        package_ids.push(package.id);
+        database.save_problem(&package).unwrap();
+        problems.retain(|x| x.id != package.id);
+        problems.push(package);
+    }
+    problems.sort_by_key(|x| x.id);
+    //a problem with a broken checker is still served, its special judge cases fail until it's fixed
+    for problem in &problems {
+        if let Err(err) = oj::problem::compile_checker(problem, &config) {
+            log::error!("Failed to build checker of problem {}: {}", problem.id, err.message);
+        }
+    }
+    *PROBLEM_LIST.lock().unwrap() = problems;
+    STORAGE.set(Box::new(database)).ok();
+    sweep_job_dirs(Path::new(&config.server.judging_dir));
+    for _ in 0..config.server.judge_workers.max(1) {
+        let config = config.clone();
+        std::thread::spawn(move || judge_worker(config));
+    }
+    let server_config = config.server.clone();
+    let mut server = HttpServer::new(move || {
         App::new()
             .wrap(Logger::default())
             .app_data(web::Data::new(config.clone()))
+            .app_data(web::PayloadConfig::new(DATA_UPLOAD_LIMIT))
             .route("/hello", web::get().to(|| async { "Hello World!" }))
             .service(greet)
+            .service(login)
+            .service(logout)
             .service(post_jobs)
             .service(get_jobs)
+            //before get_job, which would take "events" for an id
+            .service(get_jobs_events)
+            .service(get_rejudge)
+            .service(get_job_events)
             .service(get_job)
             .service(put_job)
+            .service(post_rejudge)
+            .service(delete_job)
             .service(post_users)
             .service(get_users)
             .service(get_rank_list)
             .service(post_contest)
             .service(get_contest)
             .service(get_contests)
+            .service(get_problems)
+            .service(get_problem)
+            .service(post_problem)
+            .service(put_problem)
+            .service(delete_problem)
+            .service(post_problem_data)
             // DO NOT REMOVE: used in automatic testing
             .service(exit)
     })
-        .bind(("127.0.0.1", 12345))?
-        .run()
-        .await
-}
-
-fn read_data() {
-    let mut job_lock = JOB_LIST.lock().unwrap();
-    let jobs_string = fs::read_to_string("./jobs.json").unwrap();
-    let initial_jobs: Vec<Job> = serde_json::from_str(&jobs_string).unwrap();
-    *job_lock = initial_jobs;
-    let mut user_lock = UESR_LIST.lock().unwrap();
-    let user_string = fs::read_to_string("./users.json").unwrap();
-    let initial_users: Vec<User> = serde_json::from_str(&user_string).unwrap();
-    *user_lock = initial_users;
-    let mut contest_lock = CONTEST_LIST.lock().unwrap();
-    let contest_string = fs::read_to_string("./contests.json").unwrap();
-    let initial_contests: Vec<Contest> = serde_json::from_str(&contest_string).unwrap();
-    *contest_lock = initial_contests;
-}
-
-fn save_data() {
-    let jobs_lock = JOB_LIST.lock().unwrap();
-    let jobs: String = serde_json::to_string_pretty(&*jobs_lock).unwrap();
-    fs::write("./jobs.json", jobs).unwrap();
-    let users_lock = UESR_LIST.lock().unwrap();
-    let users: String = serde_json::to_string_pretty(&*users_lock).unwrap();
-    fs::write("./users.json", users).unwrap();
-    let contests_lock = CONTEST_LIST.lock().unwrap();
-    let contests: String = serde_json::to_string_pretty(&*contests_lock).unwrap();
-    fs::write("./contests.json", contests).unwrap();
+        .bind((server_config.bind_address.as_str(), server_config.bind_port))?;
+    for address in &server_config.extra_binds {
+        server = server.bind(address)?;
+    }
+    if let Some(path) = &server_config.unix_socket {
+        //a socket file left by the last run would refuse the bind, but one still answering belongs to a living server
+        if fs::metadata(path).is_ok_and(|x| x.file_type().is_socket()) {
+            if UnixStream::connect(path).is_ok() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AddrInUse,
+                    format!("another server is listening on {}", path),
+                ));
+            }
+            fs::remove_file(path)?;
+        }
+        server = server.bind_uds(path)?;
+    }
+    server.run().await
+}
+
+///take jobs from queue one by one, judge them and publish their progress to job list
+fn judge_worker(config: Config) {
+    loop {
+        let id = JOB_QUEUE.pop();
+        let canceled = Arc::new(AtomicBool::new(false));
+        let job = {
+            let lock = JOB_LIST.lock().unwrap();
+            //a job canceled right after being taken from queue is not judged
+            let job = lock.iter().find(|x| x.id == id && !x.is_canceled()).cloned();
+            if job.is_some() {
+                RUNNING_JOBS.lock().unwrap().insert(id, canceled.clone());
+            }
+            job
+        };
+        let mut job = match job {
+            Some(job) => job,
+            None => continue,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            //a problem changed meanwhile is judged as it is now
+            let problems = PROBLEM_LIST.lock().unwrap().to_vec();
+            run_job(&mut job, &config, &problems, &canceled, &mut store_job)
+        }));
+        let message = match result {
+            Ok(Ok(())) => None,
+            Ok(Err(err)) => Some(err.message),
+            Err(_) => Some("judging panicked".to_string()),
+        };
+        if let Some(message) = message {
+            log::error!("Failed to judge job {}: {}", id, message);
+            job.system_error(&message);
+            store_job(&job);
+        }
+        RUNNING_JOBS.lock().unwrap().remove(&id);
+    }
+}
+
+///replace a job in job list and storage with its newer version, unless it was canceled meanwhile
+fn store_job(job: &Job) {
+    {
+        let mut job_list = JOB_LIST.lock().unwrap();
+        match job_list.iter_mut().find(|x| x.id == job.id) {
+            Some(old) if !old.is_canceled() || job.is_canceled() => *old = job.clone(),
+            _ => return,
+        }
+    }
+    //written without holding the job list, which every handler and worker waits for
+    if let Err(err) = storage().save_job(job) {
+        log::error!("Failed to save job {}: {}", job.id, err.message);
+    }
+    //a cancel saved before this write would be overwritten by it, so save it again
+    let canceled = JOB_LIST.lock().unwrap().iter().find(|x| x.id == job.id && x.is_canceled() && !job.is_canceled()).cloned();
+    if let Some(canceled) = canceled {
+        if let Err(err) = storage().save_job(&canceled) {
+            log::error!("Failed to save job {}: {}", job.id, err.message);
+        }
+        return;
+    }
+    JOB_EVENTS.send(job.clone()).ok();
 }
@@ -0,0 +1,435 @@
+use crate::runner::Termination;
+use crate::{build, Case, CaseResult, Config, Error, Language, Misc, Problem, ProblemType, Reason};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Cursor};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use zip::ZipArchive;
+
+///problem.json or problem.toml of a problem package, which is laid out as
+/// problem.json, data/1.in, data/1.ans, data/2.in, ..., and optionally statement.md and a checker
+#[derive(Deserialize)]
+struct Manifest {
+    id: i32,
+    name: String,
+    #[serde(rename = "type", default = "type_default")]
+    ty: ProblemType,
+    #[serde(default)]
+    misc: Misc,
+    ///limits of every case, in microseconds and bytes
+    time_limit: i64,
+    #[serde(default)]
+    memory_limit: i64,
+    ///score of the whole problem, shared evenly by cases
+    #[serde(default = "score_default")]
+    score: f64,
+    ///statement file in the package, statement.md if there is one
+    statement: Option<String>,
+}
+
+fn type_default() -> ProblemType {
+    ProblemType::Standard
+}
+
+fn score_default() -> f64 {
+    100.0
+}
+
+fn invalid(message: String) -> Error {
+    Error {
+        reason: Reason::ErrInvalidArgument,
+        code: 1,
+        message,
+    }
+}
+
+fn internal(err: impl std::fmt::Display) -> Error {
+    Error {
+        reason: Reason::ErrInternal,
+        code: 6,
+        message: format!("failed to unpack test data: {}", err),
+    }
+}
+
+impl Problem {
+    ///make sure a problem can be judged: it has cases, its packs name existing cases,
+    /// a special judge has a command or checker, an interactive problem has an interactor
+    pub fn check(&self) -> Result<(), Error> {
+        if self.cases.is_empty() {
+            return Err(invalid(format!("Problem {} has no cases.", self.id)));
+        }
+        if let Some(packing) = &self.misc.packing {
+            for case in packing.iter().flatten() {
+                if *case == 0 || *case > self.cases.len() {
+                    return Err(invalid(format!("Case {} in packing of problem {} not found.", case, self.id)));
+                }
+            }
+        }
+        if let ProblemType::Spj = self.ty {
+            if self.misc.special_judge.as_ref().is_none_or(|x| x.is_empty()) && self.misc.checker.is_none() {
+                return Err(invalid(format!("Special judge of problem {} not given.", self.id)));
+            }
+        }
+        if let ProblemType::Interactive = self.ty {
+            if self.misc.interactor.as_ref().is_none_or(|x| x.is_empty()) {
+                return Err(invalid(format!("Interactor of problem {} not given.", self.id)));
+            }
+        }
+        if self.misc.epsilon.is_some_and(|x| x.is_nan() || x < 0.0) {
+            return Err(invalid(format!("Epsilon of problem {} must not be negative.", self.id)));
+        }
+        Ok(())
+    }
+
+    ///what the server runs to judge the problem, apart from the programs: special judge, interactor and checker
+    fn commands(&self) -> (Option<&Vec<String>>, Option<&Vec<String>>, Option<&String>) {
+        (self.misc.special_judge.as_ref(), self.misc.interactor.as_ref(), self.misc.checker.as_ref())
+    }
+
+    ///Make sure a problem from a problem setter who isn't an admin runs the same commands as old, none if it's new,
+    /// as they run on the server itself, and reads test data only from data, its own directory of problem_data.
+    pub fn check_setter(&self, old: Option<&Problem>, data: &Path) -> Result<(), Error> {
+        if self.commands() != old.map_or((None, None, None), |x| x.commands()) {
+            return Err(Error {
+                reason: Reason::ErrForbidden,
+                code: 8,
+                message: "Only admins set special judges, interactors and checkers.".to_string(),
+            });
+        }
+        for file in self.cases.iter().flat_map(|x| [&x.input_file, &x.answer_file]) {
+            if !is_inside(Path::new(file), data) {
+                return Err(invalid(format!("{}: test data must be in {}", file, data.display())));
+            }
+        }
+        Ok(())
+    }
+
+    ///whether the checker source of the problem is in dir, written so or found there
+    pub fn has_checker_in(&self, dir: &Path) -> bool {
+        let Some(checker) = &self.misc.checker else {
+            return false;
+        };
+        let checker = Path::new(checker);
+        let found = checker.canonicalize().ok().zip(dir.canonicalize().ok());
+        is_inside(checker, dir) || found.is_some_and(|(checker, dir)| checker.starts_with(dir))
+    }
+}
+
+///whether path stays in dir, by how it's written, and by where it leads if it exists
+fn is_inside(path: &Path, dir: &Path) -> bool {
+    fn components(path: &Path) -> Vec<Component<'_>> {
+        path.components().filter(|x| *x != Component::CurDir).collect()
+    }
+    if path.components().any(|x| x == Component::ParentDir) || !components(path).starts_with(&components(dir)) {
+        return false;
+    }
+    match (path.canonicalize(), dir.canonicalize()) {
+        (Ok(path), Ok(dir)) => path.starts_with(dir),
+        (Err(_), _) => true,
+        (Ok(_), Err(_)) => false,
+    }
+}
+
+///the language of a checker, told by the extension of its file name
+fn checker_language<'a>(checker: &str, config: &'a Config) -> Result<&'a Language, Error> {
+    let extension = Path::new(checker).extension();
+    config
+        .languages
+        .iter()
+        .find(|x| extension.is_some() && Path::new(&x.file_name).extension() == extension)
+        .ok_or_else(|| invalid(format!("{}: no language for checker", checker)))
+}
+
+///directory the checker of a problem is built in
+fn checker_dir(config: &Config, id: i32) -> PathBuf {
+    Path::new(&config.server.problem_data).join("checkers").join(id.to_string())
+}
+
+///a command of language with %SOURCE% (or %INPUT%), %OUTPUT% and %DIR% replaced, for a checker
+fn fill(template: &[String], source: &str, dir: &Path) -> Vec<String> {
+    let output = dir.join("checker").display().to_string();
+    let dir = dir.display().to_string();
+    template
+        .iter()
+        .map(|x| x.replace("%INPUT%", source).replace("%SOURCE%", source).replace("%OUTPUT%", &output).replace("%DIR%", &dir))
+        .collect()
+}
+
+///Command running the checker of a problem, to be followed by input, output and answer files.
+pub fn checker_command(problem: &Problem, config: &Config) -> Result<Vec<String>, Error> {
+    let checker = problem.misc.checker.as_deref().unwrap_or_default();
+    let language = checker_language(checker, config)?;
+    let dir = checker_dir(config, problem.id);
+    Ok(match &language.run {
+        Some(run) => fill(run, checker, &dir),
+        None => vec![dir.join("checker").display().to_string()],
+    })
+}
+
+///Build the checker of a problem from its source, if it has one, with the language its file extension tells.
+/// It's built where it is, so that a testlib.h next to it is found. The binary is built aside and then
+/// swapped in, as judges may be running the old one; if the build fails the old one is removed,
+/// so that no case is checked by a checker of another source.
+pub fn compile_checker(problem: &Problem, config: &Config) -> Result<(), Error> {
+    static BUILD_ID: AtomicU64 = AtomicU64::new(0);
+    let checker = match &problem.misc.checker {
+        Some(checker) => checker,
+        None => return Ok(()),
+    };
+    let mut language = checker_language(checker, config)?.clone();
+    let dir = checker_dir(config, problem.id);
+    let temp = dir.with_extension(format!("{}.build", BUILD_ID.fetch_add(1, Ordering::SeqCst)));
+    for dir in [&dir, &temp] {
+        fs::create_dir_all(dir).map_err(|err| Error {
+            reason: Reason::ErrInternal,
+            code: 6,
+            message: format!("failed to create {}: {}", dir.display(), err),
+        })?;
+    }
+    if language.command.is_empty() {
+        fs::remove_dir_all(&temp).ok();
+        return Ok(());
+    }
+    language.command = fill(&language.command, checker, &temp);
+    let mut output = CaseResult::new(0);
+    let built = build(&language, &config.sandbox, &temp.display().to_string(), &Arc::default(), &mut output)
+        .and_then(|termination| match termination {
+            Termination::Exited(0) => fs::rename(temp.join("checker"), dir.join("checker")).map_err(|err| Error {
+                reason: Reason::ErrInternal,
+                code: 6,
+                message: format!("failed to put checker in place: {}", err),
+            }),
+            _ => Err(invalid(format!("{}: checker failed to build\n{}", checker, output.info))),
+        });
+    fs::remove_dir_all(&temp).ok();
+    if built.is_err() {
+        fs::remove_file(dir.join("checker")).ok();
+    }
+    built
+}
+
+///error of a problem package, naming the file at fault
+fn bad_package(path: &Path, message: impl std::fmt::Display) -> Error {
+    invalid(format!("{}: {}", path.display(), message))
+}
+
+///Read a problem package in dir, with cases 1, 2, ... found in data/ as N.in and N.ans.
+pub fn load_package(dir: &Path) -> Result<Problem, Error> {
+    let json = dir.join("problem.json");
+    let toml = dir.join("problem.toml");
+    let manifest: Manifest = if json.exists() {
+        let text = fs::read_to_string(&json).map_err(|err| bad_package(&json, err))?;
+        serde_json::from_str(&text).map_err(|err| bad_package(&json, err))?
+    } else if toml.exists() {
+        let text = fs::read_to_string(&toml).map_err(|err| bad_package(&toml, err))?;
+        toml::from_str(&text).map_err(|err| bad_package(&toml, err))?
+    } else {
+        return Err(bad_package(dir, "neither problem.json nor problem.toml found"));
+    };
+    //inputs and answers by case number
+    let data = dir.join("data");
+    let mut files: BTreeMap<usize, (Option<PathBuf>, Option<PathBuf>)> = BTreeMap::new();
+    for entry in fs::read_dir(&data).map_err(|err| bad_package(&data, err))? {
+        let path = entry.map_err(|err| bad_package(&data, err))?.path();
+        let number = path.file_stem().and_then(|x| x.to_str()).and_then(|x| x.parse::<usize>().ok());
+        match (number, path.extension().and_then(|x| x.to_str())) {
+            (Some(number), Some("in")) if number > 0 => files.entry(number).or_default().0 = Some(path),
+            (Some(number), Some("ans")) if number > 0 => files.entry(number).or_default().1 = Some(path),
+            _ => return Err(bad_package(&path, "not named N.in or N.ans")),
+        }
+    }
+    if files.is_empty() {
+        return Err(bad_package(&data, "no cases found"));
+    }
+    let count = files.len();
+    let mut cases = vec![];
+    for (number, (input, answer)) in files {
+        //packing names cases by number, so none can be left out
+        if number != cases.len() + 1 {
+            return Err(bad_package(&data.join(format!("{}.in", cases.len() + 1)), "missing"));
+        }
+        let input = input.ok_or_else(|| bad_package(&data.join(format!("{}.in", number)), "missing"))?;
+        let answer = answer.ok_or_else(|| bad_package(&data.join(format!("{}.ans", number)), "missing"))?;
+        cases.push(Case {
+            score: manifest.score / count as f64,
+            input_file: input.display().to_string(),
+            answer_file: answer.display().to_string(),
+            time_limit: manifest.time_limit,
+            memory_limit: manifest.memory_limit,
+            wall_time_limit: None,
+            output_limit: None,
+        });
+    }
+    let mut misc = manifest.misc;
+    if let Some(checker) = &misc.checker {
+        let path = dir.join(checker);
+        if !path.is_file() {
+            return Err(bad_package(&path, "checker not found"));
+        }
+        misc.checker = Some(path.display().to_string());
+    }
+    let statement = match &manifest.statement {
+        Some(name) => Some(dir.join(name)),
+        None => Some(dir.join("statement.md")).filter(|x| x.exists()),
+    };
+    let statement = match statement {
+        Some(path) => Some(fs::read_to_string(&path).map_err(|err| bad_package(&path, err))?),
+        None => None,
+    };
+    let problem = Problem {
+        id: manifest.id,
+        name: manifest.name,
+        ty: manifest.ty,
+        misc,
+        cases,
+        statement,
+    };
+    problem.check().map_err(|err| bad_package(dir, err.message))?;
+    Ok(problem)
+}
+
+///Unpack a zip archive of test data into dir, replacing what was there, and return the paths of its files.
+/// Entries reaching out of dir are refused.
+pub fn unpack(zip: &[u8], dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut archive = ZipArchive::new(Cursor::new(zip)).map_err(|err| invalid(format!("Bad zip archive: {}", err)))?;
+    //unpack next to dir first, so that a broken archive leaves the old data alone
+    let staging = dir.with_extension("upload");
+    if staging.exists() {
+        fs::remove_dir_all(&staging).map_err(internal)?;
+    }
+    let mut files = vec![];
+    let result = (|| {
+        for index in 0..archive.len() {
+            let mut entry = archive.by_index(index).map_err(|err| invalid(format!("Bad zip archive: {}", err)))?;
+            let name = match entry.enclosed_name() {
+                Some(name) => name.to_path_buf(),
+                None => return Err(invalid(format!("Bad file name {} in zip archive.", entry.name()))),
+            };
+            if entry.is_dir() {
+                fs::create_dir_all(staging.join(&name)).map_err(internal)?;
+                continue;
+            }
+            let path = staging.join(&name);
+            fs::create_dir_all(path.parent().unwrap()).map_err(internal)?;
+            io::copy(&mut entry, &mut fs::File::create(&path).map_err(internal)?).map_err(internal)?;
+            files.push(dir.join(name));
+        }
+        Ok(())
+    })();
+    if let Err(err) = result {
+        fs::remove_dir_all(&staging).ok();
+        return Err(err);
+    }
+    fs::create_dir_all(&staging).map_err(internal)?;
+    if dir.exists() {
+        fs::remove_dir_all(dir).map_err(internal)?;
+    }
+    fs::rename(&staging, dir).map_err(internal)?;
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::{FileOptions, ZipWriter};
+
+    fn archive(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(vec![]));
+        for (name, content) in files {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn unpack_replaces_old_data() {
+        let dir = std::env::temp_dir().join(format!("unpack_test_{}", std::process::id()));
+        unpack(&archive(&[("1.in", "1 2"), ("old.ans", "3")]), &dir).unwrap();
+        let files = unpack(&archive(&[("data/1.in", "2 3"), ("data/1.ans", "5")]), &dir).unwrap();
+        assert_eq!(files, vec![dir.join("data/1.ans"), dir.join("data/1.in")]);
+        assert_eq!(fs::read_to_string(dir.join("data/1.in")).unwrap(), "2 3");
+        assert!(!dir.join("old.ans").exists());
+        assert!(unpack(&archive(&[("../escape", "x")]), &dir).is_err());
+        assert!(!dir.with_file_name("escape").exists());
+        assert!(dir.join("data/1.ans").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn package(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("package_test_{}_{}", name, std::process::id()));
+        fs::create_dir_all(dir.join("data")).unwrap();
+        for (file, content) in files {
+            fs::create_dir_all(dir.join(file).parent().unwrap()).unwrap();
+            fs::write(dir.join(file), content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn package_cases_are_found_in_order() {
+        let manifest = "id = 3\nname = \"aplusb\"\ntime_limit = 1000000\n";
+        let dir = package("order", &[("problem.toml", manifest), ("statement.md", "add two numbers")]);
+        for case in 1..=10 {
+            fs::write(dir.join(format!("data/{}.in", case)), "").unwrap();
+            fs::write(dir.join(format!("data/{}.ans", case)), "").unwrap();
+        }
+        let problem = load_package(&dir).unwrap();
+        assert_eq!(problem.id, 3);
+        assert_eq!(problem.cases.len(), 10);
+        assert_eq!(problem.cases[1].input_file, dir.join("data/2.in").display().to_string());
+        assert_eq!(problem.cases[9].answer_file, dir.join("data/10.ans").display().to_string());
+        assert_eq!(problem.cases[0].score, 10.0);
+        assert_eq!(problem.statement.as_deref(), Some("add two numbers"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn package_error_names_file() {
+        let manifest = r#"{"id": 1, "name": "a", "time_limit": 1000000}"#;
+        let dir = package("missing", &[("problem.json", manifest), ("data/1.in", ""), ("data/1.ans", ""), ("data/2.in", "")]);
+        let err = load_package(&dir).err().unwrap();
+        assert!(err.message.starts_with(&dir.join("data/2.ans").display().to_string()), "{}", err.message);
+        fs::write(dir.join("data/2.out"), "").unwrap();
+        let err = load_package(&dir).err().unwrap();
+        assert!(err.message.starts_with(&dir.join("data/2.out").display().to_string()), "{}", err.message);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn packing_must_name_cases() {
+        let problem: Problem = serde_json::from_str(
+            r#"{"id": 1, "name": "a", "type": "standard", "misc": {"packing": [[1, 2]]},
+                "cases": [{"score": 100, "input_file": "1.in", "answer_file": "1.ans", "time_limit": 0, "memory_limit": 0}]}"#,
+        )
+        .unwrap();
+        assert!(problem.check().is_err());
+    }
+
+    #[test]
+    fn setter_keeps_commands_and_data_in_place() {
+        let problem = |misc: &str, input: &str| -> Problem {
+            serde_json::from_str(&format!(
+                r#"{{"id": 1, "name": "a", "type": "spj", "misc": {},
+                    "cases": [{{"score": 100, "input_file": "{}", "answer_file": "problem_data/1/1.ans",
+                    "time_limit": 0, "memory_limit": 0}}]}}"#,
+                misc, input
+            ))
+            .unwrap()
+        };
+        let data = Path::new("problem_data/1");
+        let old = problem(r#"{"special_judge": ["./spj"]}"#, "problem_data/1/1.in");
+        assert!(old.check_setter(Some(&old), data).is_ok());
+        assert!(old.check_setter(None, data).is_err());
+        let changed = problem(r#"{"special_judge": ["sh", "-c", "id"]}"#, "problem_data/1/1.in");
+        assert!(changed.check_setter(Some(&old), data).is_err());
+        for input in ["/etc/shadow", "problem_data/1/../2/1.in", "problem_data/2/1.in"] {
+            assert!(problem(r#"{"special_judge": ["./spj"]}"#, input).check_setter(Some(&old), data).is_err());
+        }
+    }
+}
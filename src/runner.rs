@@ -0,0 +1,468 @@
+use crate::sandbox::Jail;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+///how often a running program checks whether it's canceled
+const CANCEL_POLL: Duration = Duration::from_millis(50);
+///how many times the memory limit a program may reserve as address space, of which it uses only a part,
+/// as runtimes like the JVM or Go reserve far more than they touch
+const ADDRESS_SPACE_FACTOR: u64 = 16;
+
+///limits on a single run of a program: time is wall-clock, cpu is user and system time,
+/// memory is in bytes, and 0 means unlimited for cpu and memory
+#[derive(Clone, Copy)]
+pub struct Limit {
+    pub time: Duration,
+    pub cpu: Duration,
+    pub memory: u64,
+}
+
+///how a program run ended
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Termination {
+    Exited(i32),
+    Signaled(i32),
+    TimedOut,
+    ///killed because its judging was canceled
+    Canceled,
+}
+
+impl fmt::Display for Termination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Termination::Exited(code) => write!(f, "exited with code {}", code),
+            Termination::Signaled(signal) => match signal_name(*signal) {
+                Some(name) => write!(f, "killed by signal {} ({})", signal, name),
+                None => write!(f, "killed by signal {}", signal),
+            },
+            Termination::TimedOut => write!(f, "timed out"),
+            Termination::Canceled => write!(f, "canceled"),
+        }
+    }
+}
+
+///name of a signal a program commonly dies of
+fn signal_name(signal: i32) -> Option<&'static str> {
+    Some(match signal {
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGILL => "SIGILL",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGXCPU => "SIGXCPU",
+        libc::SIGXFSZ => "SIGXFSZ",
+        libc::SIGSYS => "SIGSYS",
+        _ => return None,
+    })
+}
+
+///what a program run used, memory is the peak resident set size in bytes
+pub struct Usage {
+    pub termination: Termination,
+    pub memory: u64,
+    ///the kernel refused to give the program more memory, or it mapped more writable memory than its limit
+    pub out_of_memory: bool,
+    ///user and system time of the program and the children it waited for
+    pub cpu_time: Duration,
+    pub wall_time: Duration,
+}
+
+impl Usage {
+    ///whether the run broke its memory limit, by reaching it or by being refused an allocation beyond it
+    pub fn is_memory_exceeded(&self, limit: &Limit) -> bool {
+        limit.memory != 0 && (self.memory >= limit.memory || self.out_of_memory)
+    }
+}
+
+///a program started by spawn()
+pub struct Process {
+    pub child: Child,
+    traced: bool,
+    started: Instant,
+}
+
+///Spawn a command in its own process group, with its address space limited to a multiple of limit.memory
+/// as a backstop, the limit itself is kept on resident memory by wait().
+/// The program is traced, so that refused allocations and ones beyond the limit are noticed
+/// and its peak memory is read right before it exits;
+/// where tracing is forbidden it runs untraced, and memory is taken from rusage instead.
+/// If a jail is given, the program enters it before exec.
+/// wait() must be called from the same thread.
+pub fn spawn(command: &mut Command, limit: &Limit, jail: Option<Jail>) -> io::Result<Process> {
+    //pre_exec hooks pile up on a command, so register one and switch tracing off through it when falling back
+    let tracing = Arc::new(AtomicBool::new(true));
+    let memory = limit.memory;
+    {
+        let tracing = tracing.clone();
+        unsafe {
+            command.pre_exec(move || {
+                if libc::setpgid(0, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                if memory != 0 {
+                    let space = memory.saturating_mul(ADDRESS_SPACE_FACTOR);
+                    let rlimit = libc::rlimit { rlim_cur: space, rlim_max: space };
+                    if libc::setrlimit(libc::RLIMIT_AS, &rlimit) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                if tracing.load(Ordering::SeqCst)
+                    && libc::ptrace(libc::PTRACE_TRACEME, 0, ptr::null_mut::<libc::c_void>(), ptr::null_mut::<libc::c_void>()) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                //after being traced, as the jail forbids ptrace
+                if let Some(jail) = &jail {
+                    jail.enter()?;
+                }
+                if tracing.load(Ordering::SeqCst) {
+                    trace_allocations()?;
+                }
+                Ok(())
+            });
+        }
+    }
+    match spawn_with(command, true) {
+        Err(err) if matches!(err.raw_os_error(), Some(libc::EPERM) | Some(libc::EINVAL) | Some(libc::ENOSYS)) => {
+            log::warn!("Cannot trace judged program ({}), memory usage will be less accurate", err);
+            tracing.store(false, Ordering::SeqCst);
+            spawn_with(command, false)
+        }
+        result => result,
+    }
+}
+
+fn spawn_with(command: &mut Command, traced: bool) -> io::Result<Process> {
+    let started = Instant::now();
+    let child = command.spawn()?;
+    if traced {
+        //the child stops at exec, from now on follow its threads and children as well
+        let pid = child.id() as libc::pid_t;
+        let mut status = 0;
+        if unsafe { libc::waitpid(pid, &mut status, libc::__WALL) } != pid {
+            return Err(io::Error::last_os_error());
+        }
+        let options = libc::PTRACE_O_TRACEEXIT
+            | libc::PTRACE_O_TRACEEXEC
+            | libc::PTRACE_O_TRACESECCOMP
+            | libc::PTRACE_O_TRACESYSGOOD
+            | libc::PTRACE_O_TRACECLONE
+            | libc::PTRACE_O_TRACEFORK
+            | libc::PTRACE_O_TRACEVFORK
+            | libc::PTRACE_O_EXITKILL;
+        unsafe {
+            libc::ptrace(libc::PTRACE_SETOPTIONS, pid, ptr::null_mut::<libc::c_void>(), options as usize as *mut libc::c_void);
+        }
+        resume(pid, libc::PTRACE_CONT, 0);
+    }
+    Ok(Process { child, traced, started })
+}
+
+///Install a seccomp filter that hands mmap, mremap and brk to the tracer, so that it can see what they give.
+fn trace_allocations() -> io::Result<()> {
+    const LOAD_NR: u16 = (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16;
+    const JUMP_EQ: u16 = (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16;
+    const RETURN: u16 = (libc::BPF_RET | libc::BPF_K) as u16;
+    let mut filter = [
+        libc::sock_filter { code: LOAD_NR, jt: 0, jf: 0, k: 0 },
+        libc::sock_filter { code: JUMP_EQ, jt: 3, jf: 0, k: libc::SYS_mmap as u32 },
+        libc::sock_filter { code: JUMP_EQ, jt: 2, jf: 0, k: libc::SYS_mremap as u32 },
+        libc::sock_filter { code: JUMP_EQ, jt: 1, jf: 0, k: libc::SYS_brk as u32 },
+        libc::sock_filter { code: RETURN, jt: 0, jf: 0, k: libc::SECCOMP_RET_ALLOW },
+        libc::sock_filter { code: RETURN, jt: 0, jf: 0, k: libc::SECCOMP_RET_TRACE },
+    ];
+    let program = libc::sock_fprog { len: filter.len() as u16, filter: filter.as_mut_ptr() };
+    unsafe {
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0
+            || libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &program as *const libc::sock_fprog) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn resume(tid: libc::pid_t, request: libc::c_uint, signal: libc::c_int) {
+    unsafe {
+        libc::ptrace(request, tid, ptr::null_mut::<libc::c_void>(), signal as usize as *mut libc::c_void);
+    }
+}
+
+///Wait for a program started by spawn() until it ends, runs out of time or memory or gets canceled, and collect its usage.
+/// a program out of time or memory or canceled is killed together with everything it started.
+pub fn wait(process: &mut Process, limit: &Limit, canceled: &Arc<AtomicBool>) -> io::Result<Usage> {
+    let pid = process.child.id() as libc::pid_t;
+    let timed_out = Arc::new(AtomicBool::new(false));
+    //resident memory of the whole process group, at its highest seen by the watchdog
+    let resident = Arc::new(AtomicU64::new(0));
+    let (finished, finish) = mpsc::channel::<()>();
+    let watchdog = {
+        let timed_out = timed_out.clone();
+        let resident = resident.clone();
+        let canceled = canceled.clone();
+        let deadline = Instant::now() + limit.time;
+        let cpu = limit.cpu;
+        let memory = limit.memory;
+        thread::spawn(move || loop {
+            let now = Instant::now();
+            //a program may fork, as a run template going through sh does, so all of its group counts
+            let members = if cpu.is_zero() && memory == 0 { Vec::new() } else { group(pid) };
+            let used_cpu: Duration = members.iter().filter_map(|&x| cpu_time(x)).sum();
+            let over = now >= deadline || (!cpu.is_zero() && used_cpu >= cpu);
+            if over {
+                timed_out.store(true, Ordering::SeqCst);
+            }
+            let mut out_of_memory = false;
+            if memory != 0 {
+                let used: u64 = members.iter().filter_map(|&x| status_memory(x, "VmRSS:")).sum();
+                resident.fetch_max(used, Ordering::SeqCst);
+                out_of_memory = used >= memory;
+            }
+            if over || out_of_memory || canceled.load(Ordering::SeqCst) {
+                unsafe { libc::killpg(pid, libc::SIGKILL) };
+                return;
+            }
+            if let Err(mpsc::RecvTimeoutError::Timeout) = finish.recv_timeout((deadline - now).min(CANCEL_POLL)) {
+                continue;
+            }
+            return;
+        })
+    };
+    let mut memory = 0;
+    let mut out_of_memory = false;
+    let options = libc::__WALL | libc::__WNOTHREAD;
+    loop {
+        //look at the program's exit without reaping it, so that the watchdog never kills a recycled pid
+        let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+        if unsafe { libc::waitid(libc::P_ALL, 0, &mut info, libc::WEXITED | libc::WSTOPPED | libc::WNOWAIT | options) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let tid = unsafe { info.si_pid() };
+        if tid == pid && info.si_code != libc::CLD_TRAPPED && info.si_code != libc::CLD_STOPPED {
+            break;
+        }
+        let mut status = 0;
+        if unsafe { libc::waitpid(tid, &mut status, options) } != tid || !libc::WIFSTOPPED(status) {
+            continue;
+        }
+        let signal = libc::WSTOPSIG(status);
+        let event = status >> 16;
+        if event == libc::PTRACE_EVENT_SECCOMP {
+            //an allocation is about to happen, stop again when it returns
+            resume(tid, libc::PTRACE_SYSCALL, 0);
+        } else if signal == libc::SIGTRAP | 0x80 {
+            //zeroed memory is never touched before it's read, so allocations are counted as they are mapped
+            let beyond = limit.memory != 0 && committed_memory(tid).is_some_and(|x| x >= limit.memory);
+            out_of_memory |= beyond || syscall_result(tid) == Some(-libc::ENOMEM as i64);
+            if beyond {
+                unsafe { libc::killpg(pid, libc::SIGKILL) };
+            }
+            resume(tid, libc::PTRACE_CONT, 0);
+        } else if event != 0 || signal == libc::SIGSTOP {
+            if event == libc::PTRACE_EVENT_EXIT {
+                memory = memory.max(status_memory(tid, "VmHWM:").unwrap_or(0));
+            }
+            resume(tid, libc::PTRACE_CONT, 0);
+        } else {
+            //a real signal, pass it on
+            resume(tid, libc::PTRACE_CONT, signal);
+        }
+    }
+    drop(finished);
+    watchdog.join().ok();
+    //clean up whatever the program left running, then reap it
+    unsafe { libc::killpg(pid, libc::SIGKILL) };
+    let mut status = 0;
+    let mut rusage: libc::rusage = unsafe { mem::zeroed() };
+    if unsafe { libc::wait4(pid, &mut status, libc::__WALL, &mut rusage) } != pid {
+        return Err(io::Error::last_os_error());
+    }
+    let wall_time = process.started.elapsed();
+    while unsafe { libc::waitpid(-1, ptr::null_mut(), libc::WNOHANG | options) } > 0 {}
+    if !process.traced {
+        memory = rusage.ru_maxrss as u64 * 1024;
+    }
+    memory = memory.max(resident.load(Ordering::SeqCst));
+    let seconds = |x: libc::timeval| Duration::new(x.tv_sec as u64, x.tv_usec as u32 * 1000);
+    let cpu_time = seconds(rusage.ru_utime) + seconds(rusage.ru_stime);
+    //the watchdog only looks now and then, a program may have gone beyond the cpu limit in between
+    let termination = if timed_out.load(Ordering::SeqCst) || (!limit.cpu.is_zero() && cpu_time > limit.cpu) {
+        Termination::TimedOut
+    } else if canceled.load(Ordering::SeqCst) {
+        Termination::Canceled
+    } else if libc::WIFSIGNALED(status) {
+        Termination::Signaled(libc::WTERMSIG(status))
+    } else {
+        Termination::Exited(libc::WEXITSTATUS(status))
+    };
+    Ok(Usage { termination, memory, out_of_memory, cpu_time, wall_time })
+}
+
+///user and system time a living process has used so far, from all of its threads and the children it waited for
+fn cpu_time(pid: libc::pid_t) -> Option<Duration> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    //fields after the command name, which may have spaces, start from the state, the third one
+    let fields: Vec<&str> = stat.get(stat.rfind(')')? + 2..)?.split(' ').collect();
+    //utime, stime, cutime and cstime
+    let ticks = fields.get(11..15)?.iter().map(|x| x.parse::<u64>().ok()).sum::<Option<u64>>()?;
+    let per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if per_second <= 0 {
+        return None;
+    }
+    Some(Duration::from_micros(ticks * 1_000_000 / per_second as u64))
+}
+
+///return value of the syscall a tracee stopped at the exit of
+fn syscall_result(tid: libc::pid_t) -> Option<i64> {
+    //struct ptrace_syscall_info: op at byte 0, and for syscall exit the return value at byte 24
+    let mut info = [0u64; 12];
+    let size = unsafe {
+        libc::ptrace(libc::PTRACE_GET_SYSCALL_INFO, tid, mem::size_of_val(&info), info.as_mut_ptr())
+    };
+    if size < 32 || info[0] & 0xff != libc::PTRACE_SYSCALL_INFO_EXIT as u64 {
+        return None;
+    }
+    Some(info[3] as i64)
+}
+
+///read a memory size of a living process from its status, VmHWM: for the peak resident set size, VmRSS: for the current one
+fn status_memory(tid: libc::pid_t, field: &str) -> Option<u64> {
+    let status = fs::read_to_string(format!("/proc/{}/status", tid)).ok()?;
+    let line = status.lines().find(|x| x.starts_with(field))?;
+    let kb: u64 = line.trim_start_matches(field).trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kb * 1024)
+}
+
+///size of the writable private and anonymous mappings of a living process, which the kernel charges it for
+/// as soon as they are mapped, unlike address space only reserved
+fn committed_memory(tid: libc::pid_t) -> Option<u64> {
+    let maps = fs::read_to_string(format!("/proc/{}/maps", tid)).ok()?;
+    let mut total = 0;
+    for line in maps.lines() {
+        //range, permissions, offset, device, inode and path
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (range, permissions, inode) = (fields.first()?, fields.get(1)?, fields.get(4)?);
+        if permissions.get(1..2) == Some("w") && (permissions.ends_with('p') || *inode == "0") {
+            let (start, end) = range.split_once('-')?;
+            total += u64::from_str_radix(end, 16).ok()? - u64::from_str_radix(start, 16).ok()?;
+        }
+    }
+    Some(total)
+}
+
+///living processes in the process group pgid
+fn group(pgid: libc::pid_t) -> Vec<libc::pid_t> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|x| x.ok()?.file_name().to_str()?.parse::<libc::pid_t>().ok())
+        .filter(|&pid| {
+            //the process group is the third field after the command name
+            fs::read_to_string(format!("/proc/{}/stat", pid)).is_ok_and(|stat| {
+                stat.rfind(')').and_then(|x| stat.get(x + 2..)).and_then(|x| x.split(' ').nth(2)) == Some(&pgid.to_string())
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Stdio;
+
+    fn run(script: &str, limit: &Limit) -> Usage {
+        let mut command = Command::new("python3");
+        command.args(["-c", script]).stdout(Stdio::null()).stderr(Stdio::null());
+        let mut process = spawn(&mut command, limit, None).unwrap();
+        wait(&mut process, limit, &Default::default()).unwrap()
+    }
+
+    #[test]
+    fn allocation_beyond_limit_is_memory_exceeded() {
+        let limit = Limit { time: Duration::from_secs(10), cpu: Duration::ZERO, memory: 256 << 20 };
+        let usage = run("b = bytearray(1 << 30)", &limit);
+        assert!(usage.is_memory_exceeded(&limit));
+    }
+
+    #[test]
+    fn reserved_memory_is_not_counted() {
+        let limit = Limit { time: Duration::from_secs(10), cpu: Duration::ZERO, memory: 256 << 20 };
+        let usage = run("import mmap; m = mmap.mmap(-1, 1 << 30, prot=0)", &limit);
+        assert_eq!(usage.termination, Termination::Exited(0));
+        assert!(!usage.is_memory_exceeded(&limit));
+    }
+
+    #[test]
+    fn segfault_is_not_memory_exceeded() {
+        let limit = Limit { time: Duration::from_secs(10), cpu: Duration::ZERO, memory: 256 << 20 };
+        let usage = run("import ctypes; ctypes.string_at(0)", &limit);
+        assert_eq!(usage.termination, Termination::Signaled(libc::SIGSEGV));
+        assert!(!usage.is_memory_exceeded(&limit));
+        assert!(usage.memory > 0);
+    }
+
+    #[test]
+    fn termination_names_signal() {
+        assert_eq!(Termination::Signaled(libc::SIGSEGV).to_string(), "killed by signal 11 (SIGSEGV)");
+        assert_eq!(Termination::Signaled(64).to_string(), "killed by signal 64");
+        assert_eq!(Termination::Exited(101).to_string(), "exited with code 101");
+    }
+
+    #[test]
+    fn cpu_limit_ignores_sleeping() {
+        let limit = Limit { time: Duration::from_secs(10), cpu: Duration::from_millis(500), memory: 0 };
+        let usage = run("import time; time.sleep(1)", &limit);
+        assert_eq!(usage.termination, Termination::Exited(0));
+        assert!(usage.wall_time >= Duration::from_secs(1));
+        assert!(usage.cpu_time < Duration::from_millis(500));
+        let usage = run("while True: pass", &limit);
+        assert_eq!(usage.termination, Termination::TimedOut);
+        assert!(usage.cpu_time >= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn cpu_limit_counts_children() {
+        let limit = Limit { time: Duration::from_secs(10), cpu: Duration::from_millis(500), memory: 0 };
+        let usage = run("import os\nif os.fork() == 0:\n    while True: pass\nos.wait()", &limit);
+        assert_eq!(usage.termination, Termination::TimedOut);
+        assert!(usage.wall_time < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn endless_loop_times_out() {
+        let limit = Limit { time: Duration::from_millis(500), cpu: Duration::ZERO, memory: 0 };
+        let usage = run("while True: pass", &limit);
+        assert_eq!(usage.termination, Termination::TimedOut);
+    }
+
+    #[test]
+    fn canceled_program_is_killed() {
+        let limit = Limit { time: Duration::from_secs(10), cpu: Duration::ZERO, memory: 0 };
+        let mut command = Command::new("python3");
+        command.args(["-c", "while True: pass"]);
+        let mut process = spawn(&mut command, &limit, None).unwrap();
+        let canceled = Arc::new(AtomicBool::new(false));
+        {
+            let canceled = canceled.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(200));
+                canceled.store(true, Ordering::SeqCst);
+            });
+        }
+        let start = Instant::now();
+        let usage = wait(&mut process, &limit, &canceled).unwrap();
+        assert_eq!(usage.termination, Termination::Canceled);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+}
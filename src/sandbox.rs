@@ -0,0 +1,486 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::process::Command;
+use std::ptr;
+
+///x86_64 syscalls a judged program or a compiler may make, calling any other kills the program
+const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+    //files
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_preadv,
+    libc::SYS_pwritev,
+    libc::SYS_open,
+    libc::SYS_openat,
+    libc::SYS_creat,
+    libc::SYS_close,
+    libc::SYS_close_range,
+    libc::SYS_lseek,
+    libc::SYS_stat,
+    libc::SYS_fstat,
+    libc::SYS_lstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_statfs,
+    libc::SYS_fstatfs,
+    libc::SYS_access,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_readlink,
+    libc::SYS_readlinkat,
+    libc::SYS_getdents,
+    libc::SYS_getdents64,
+    libc::SYS_getcwd,
+    libc::SYS_chdir,
+    libc::SYS_fchdir,
+    libc::SYS_mkdir,
+    libc::SYS_mkdirat,
+    libc::SYS_rmdir,
+    libc::SYS_rename,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_link,
+    libc::SYS_linkat,
+    libc::SYS_unlink,
+    libc::SYS_unlinkat,
+    libc::SYS_symlink,
+    libc::SYS_symlinkat,
+    libc::SYS_chmod,
+    libc::SYS_fchmod,
+    libc::SYS_fchmodat,
+    libc::SYS_umask,
+    libc::SYS_truncate,
+    libc::SYS_ftruncate,
+    libc::SYS_fallocate,
+    libc::SYS_fadvise64,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_flock,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_dup,
+    libc::SYS_dup2,
+    libc::SYS_dup3,
+    libc::SYS_pipe,
+    libc::SYS_pipe2,
+    libc::SYS_sendfile,
+    libc::SYS_copy_file_range,
+    libc::SYS_splice,
+    libc::SYS_utimensat,
+    libc::SYS_select,
+    libc::SYS_pselect6,
+    libc::SYS_poll,
+    libc::SYS_ppoll,
+    libc::SYS_epoll_create,
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_wait,
+    libc::SYS_epoll_pwait,
+    libc::SYS_eventfd2,
+    libc::SYS_memfd_create,
+    //memory
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_mincore,
+    libc::SYS_msync,
+    libc::SYS_membarrier,
+    //processes and threads, clone is looked at apart
+    libc::SYS_fork,
+    libc::SYS_vfork,
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_wait4,
+    libc::SYS_waitid,
+    libc::SYS_kill,
+    libc::SYS_tkill,
+    libc::SYS_tgkill,
+    libc::SYS_getpid,
+    libc::SYS_getppid,
+    libc::SYS_gettid,
+    libc::SYS_getpgrp,
+    libc::SYS_getpgid,
+    libc::SYS_getsid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_getresuid,
+    libc::SYS_getresgid,
+    libc::SYS_getgroups,
+    libc::SYS_arch_prctl,
+    libc::SYS_prctl,
+    libc::SYS_set_tid_address,
+    libc::SYS_set_robust_list,
+    libc::SYS_get_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_futex,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_sched_getparam,
+    libc::SYS_sched_getscheduler,
+    libc::SYS_getrlimit,
+    libc::SYS_prlimit64,
+    libc::SYS_getrusage,
+    libc::SYS_getpriority,
+    //signals
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_rt_sigsuspend,
+    libc::SYS_rt_sigtimedwait,
+    libc::SYS_sigaltstack,
+    libc::SYS_pause,
+    libc::SYS_alarm,
+    libc::SYS_getitimer,
+    libc::SYS_setitimer,
+    //time and system
+    libc::SYS_nanosleep,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_gettimeofday,
+    libc::SYS_time,
+    libc::SYS_times,
+    libc::SYS_uname,
+    libc::SYS_sysinfo,
+    libc::SYS_getrandom,
+    //sockets, the network itself is cut off unless allowed
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_connect,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_getsockopt,
+    libc::SYS_setsockopt,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_shutdown,
+];
+
+///namespaces a program may not make with clone
+const CLONE_NAMESPACES: libc::c_int = libc::CLONE_NEWUSER
+    | libc::CLONE_NEWNS
+    | libc::CLONE_NEWPID
+    | libc::CLONE_NEWNET
+    | libc::CLONE_NEWIPC
+    | libc::CLONE_NEWUTS
+    | libc::CLONE_NEWCGROUP;
+
+///seccomp_data.arch of x86_64 syscalls, the i386 ones made through int 0x80 have another
+const AUDIT_ARCH_X86_64: u32 = 0xc000_003e;
+///syscall number bit of the x32 ABI
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+///isolation of judged programs, given in config
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Sandbox {
+    #[serde(default)]
+    pub enabled: bool,
+    ///also isolate compilers
+    #[serde(default)]
+    pub compile: bool,
+    ///keep network of the host, it's cut off by default
+    #[serde(default)]
+    network: bool,
+    #[serde(default = "max_processes_default")]
+    max_processes: u64,
+    #[serde(default = "max_open_files_default")]
+    max_open_files: u64,
+    ///largest file a program can write, in bytes
+    #[serde(default = "max_file_size_default")]
+    max_file_size: u64,
+    ///run as this user and group instead of the server's
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+fn max_processes_default() -> u64 {
+    64
+}
+
+fn max_open_files_default() -> u64 {
+    64
+}
+
+fn max_file_size_default() -> u64 {
+    64 << 20
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Sandbox {
+            enabled: false,
+            compile: false,
+            network: false,
+            max_processes: max_processes_default(),
+            max_open_files: max_open_files_default(),
+            max_file_size: max_file_size_default(),
+            uid: None,
+            gid: None,
+        }
+    }
+}
+
+///everything a forked child needs to enter the sandbox, prepared before fork so that nothing is allocated after it
+pub struct Jail {
+    sandbox: Sandbox,
+    dir: CString,
+    cwd: CString,
+    ///writable mount points of the host with the flags to keep, all remounted read-only
+    mounts: Vec<(CString, libc::c_ulong)>,
+    filter: Vec<libc::sock_filter>,
+}
+
+impl Sandbox {
+    ///prepare to lock a program working in cwd in dir, the only place it can write to
+    pub fn jail(&self, dir: &Path, cwd: &Path) -> io::Result<Jail> {
+        Ok(Jail {
+            sandbox: self.clone(),
+            dir: CString::new(dir.canonicalize()?.as_os_str().as_bytes())?,
+            cwd: CString::new(cwd.canonicalize()?.as_os_str().as_bytes())?,
+            mounts: writable_mounts()?,
+            filter: allow_filter(),
+        })
+    }
+
+    ///prepare a jail in dir for a compiler, which stays in the server's working directory
+    pub fn confine(&self, command: &mut Command, dir: &Path) -> io::Result<Jail> {
+        //temporary files of compilers can't go to the read-only /tmp
+        command.env("TMPDIR", dir.canonicalize()?);
+        self.jail(dir, &env::current_dir()?)
+    }
+}
+
+impl Jail {
+    ///Called in the child between fork and exec: give it private namespaces without network,
+    /// every mount read-only with only its own directory writable, tight resource limits,
+    /// another user if asked, and allow only the syscalls of ALLOWED_SYSCALLS.
+    pub fn enter(&self) -> io::Result<()> {
+        let mut namespaces = libc::CLONE_NEWNS | libc::CLONE_NEWIPC | libc::CLONE_NEWUTS;
+        if !self.sandbox.network {
+            namespaces |= libc::CLONE_NEWNET;
+        }
+        let root = c"/".as_ptr();
+        let dir = self.dir.as_ptr();
+        unsafe {
+            check(libc::unshare(namespaces))?;
+            check(libc::mount(ptr::null(), root, ptr::null(), libc::MS_REC | libc::MS_PRIVATE, ptr::null()))?;
+            check(libc::mount(dir, dir, ptr::null(), libc::MS_BIND | libc::MS_REC, ptr::null()))?;
+            //a remount covers a single mount, so every one of them, like /tmp or /dev/shm, is made read-only
+            for (target, flags) in &self.mounts {
+                check(libc::mount(
+                    ptr::null(),
+                    target.as_ptr(),
+                    ptr::null(),
+                    libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY | libc::MS_NOSUID | flags,
+                    ptr::null(),
+                ))?;
+            }
+            //the working directory was entered before the mounts, enter it again to see them
+            check(libc::chdir(self.cwd.as_ptr()))?;
+            set_limit(libc::RLIMIT_NPROC, self.sandbox.max_processes)?;
+            set_limit(libc::RLIMIT_NOFILE, self.sandbox.max_open_files)?;
+            set_limit(libc::RLIMIT_FSIZE, self.sandbox.max_file_size)?;
+            set_limit(libc::RLIMIT_CORE, 0)?;
+            if let Some(gid) = self.sandbox.gid {
+                check(libc::setgroups(0, ptr::null()))?;
+                check(libc::setgid(gid))?;
+            }
+            if let Some(uid) = self.sandbox.uid {
+                check(libc::setuid(uid))?;
+            }
+            let program = libc::sock_fprog { len: self.filter.len() as u16, filter: self.filter.as_ptr() as *mut _ };
+            check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+            check(libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &program as *const libc::sock_fprog))?;
+        }
+        Ok(())
+    }
+}
+
+///whether a program was stopped by the sandbox: a syscall not allowed, or a file larger than allowed
+pub fn is_violation(signal: i32) -> bool {
+    signal == libc::SIGSYS || signal == libc::SIGXFSZ
+}
+
+///Writable mount points, from /proc/self/mountinfo, along with their nodev, noexec and atime flags,
+/// which a bind remount would otherwise drop.
+fn writable_mounts() -> io::Result<Vec<(CString, libc::c_ulong)>> {
+    let mut mounts = Vec::new();
+    for line in fs::read_to_string("/proc/self/mountinfo")?.lines() {
+        //id, parent id, device, root, mount point, mount options and more
+        let fields: Vec<&str> = line.split(' ').collect();
+        let (Some(target), Some(options)) = (fields.get(4), fields.get(5)) else {
+            continue;
+        };
+        let mut flags = 0;
+        for option in options.split(',') {
+            flags |= match option {
+                "ro" => libc::MS_RDONLY,
+                "nodev" => libc::MS_NODEV,
+                "noexec" => libc::MS_NOEXEC,
+                "noatime" => libc::MS_NOATIME,
+                "nodiratime" => libc::MS_NODIRATIME,
+                "relatime" => libc::MS_RELATIME,
+                _ => 0,
+            };
+        }
+        if flags & libc::MS_RDONLY == 0 {
+            mounts.push((CString::new(unescape(target))?, flags));
+        }
+    }
+    Ok(mounts)
+}
+
+///undo the octal escapes of spaces and the like in a mount point
+fn unescape(path: &str) -> Vec<u8> {
+    let bytes = path.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let code = bytes.get(index + 1..index + 4).and_then(|x| u8::from_str_radix(std::str::from_utf8(x).ok()?, 8).ok());
+        match code {
+            Some(code) if bytes[index] == b'\\' => {
+                result.push(code);
+                index += 4;
+            }
+            _ => {
+                result.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+    result
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn set_limit(resource: libc::__rlimit_resource_t, value: u64) -> io::Result<()> {
+    let rlimit = libc::rlimit { rlim_cur: value, rlim_max: value };
+    check(unsafe { libc::setrlimit(resource, &rlimit) })
+}
+
+///Seccomp filter killing a program on anything but ALLOWED_SYSCALLS of x86_64, or a clone into new namespaces.
+/// clone3 fails with ENOSYS, as its flags can't be looked at, so that libc falls back to clone.
+fn allow_filter() -> Vec<libc::sock_filter> {
+    const LOAD: u16 = (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16;
+    const JUMP_EQ: u16 = (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16;
+    const JUMP_SET: u16 = (libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K) as u16;
+    const RETURN: u16 = (libc::BPF_RET | libc::BPF_K) as u16;
+    //offsets in struct seccomp_data, the low half of the first argument on a little endian machine
+    const NR: u32 = 0;
+    const ARCH: u32 = 4;
+    const FIRST_ARGUMENT: u32 = 16;
+    let instruction = |code, jt, jf, k| libc::sock_filter { code, jt, jf, k };
+    let kill = instruction(RETURN, 0, 0, libc::SECCOMP_RET_KILL_PROCESS);
+    let allow = instruction(RETURN, 0, 0, libc::SECCOMP_RET_ALLOW);
+    let mut filter = vec![
+        instruction(LOAD, 0, 0, ARCH),
+        instruction(JUMP_EQ, 1, 0, AUDIT_ARCH_X86_64),
+        kill,
+        instruction(LOAD, 0, 0, NR),
+        instruction(JUMP_SET, 0, 1, X32_SYSCALL_BIT),
+        kill,
+        instruction(JUMP_EQ, 0, 1, libc::SYS_clone3 as u32),
+        instruction(RETURN, 0, 0, libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32),
+        instruction(JUMP_EQ, 0, 4, libc::SYS_clone as u32),
+        instruction(LOAD, 0, 0, FIRST_ARGUMENT),
+        instruction(JUMP_SET, 0, 1, CLONE_NAMESPACES as u32),
+        kill,
+        allow,
+    ];
+    for nr in ALLOWED_SYSCALLS {
+        filter.push(instruction(JUMP_EQ, 0, 1, *nr as u32));
+        filter.push(allow);
+    }
+    filter.push(kill);
+    filter
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{self, Limit, Termination};
+    use std::fs;
+    use std::process::Stdio;
+    use std::time::Duration;
+
+    fn run(script: &str, dir: &Path) -> Termination {
+        let sandbox = Sandbox { enabled: true, ..Default::default() };
+        let limit = Limit { time: Duration::from_secs(10), cpu: Duration::ZERO, memory: 0 };
+        let mut command = Command::new("python3");
+        command.args(["-c", script]).current_dir(dir).stdout(Stdio::null()).stderr(Stdio::null());
+        let jail = sandbox.jail(dir, dir).unwrap();
+        let mut process = runner::spawn(&mut command, &limit, Some(jail)).unwrap();
+        runner::wait(&mut process, &limit, &Default::default()).unwrap().termination
+    }
+
+    fn scratch(name: &str) -> std::path::PathBuf {
+        let dir = env::temp_dir().join(format!("sandbox_test_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn only_working_directory_is_writable() {
+        let dir = scratch("write");
+        assert_eq!(run("open('inside', 'w').write('ok')", &dir), Termination::Exited(0));
+        assert_ne!(run(&format!("open('{}', 'w')", env::temp_dir().join("outside").display()), &dir), Termination::Exited(0));
+        //other mounts than the root, when there are any
+        for other in ["/dev/shm", "/run", "/home"] {
+            assert_ne!(run(&format!("open('{}/outside', 'w')", other), &dir), Termination::Exited(0));
+        }
+        assert_eq!(run("open('/dev/null', 'w').write('ok')", &dir), Termination::Exited(0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn network_is_cut_off() {
+        let dir = scratch("network");
+        let script = "import socket; socket.create_connection(('1.1.1.1', 53), timeout=1)";
+        assert_ne!(run(script, &dir), Termination::Exited(0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn syscall_not_allowed_is_violation() {
+        let dir = scratch("syscall");
+        //getpid is allowed, but not through the x32 ABI
+        let syscalls = [libc::SYS_mount, libc::SYS_userfaultfd, libc::SYS_unshare, libc::SYS_getpid | X32_SYSCALL_BIT as libc::c_long];
+        for nr in syscalls {
+            let script = format!("import ctypes; ctypes.CDLL(None).syscall({}, 0, 0, 0, 0, 0)", nr);
+            match run(&script, &dir) {
+                Termination::Signaled(signal) => assert!(is_violation(signal)),
+                termination => panic!("not killed on {}: {:?}", nr, termination),
+            }
+        }
+        let script = format!("import ctypes; ctypes.CDLL(None).syscall({}, 0, 0, 0, 0, 0)", libc::SYS_getpid);
+        assert_eq!(run(&script, &dir), Termination::Exited(0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,554 @@
+use crate::{Contest, Error, GetJob, Job, JobSortKey, Problem, Reason, Rejudge, SortOrder, User};
+use rusqlite::types::ToSql;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+///where jobs, users, contests, problems and rejudges are kept across restarts, every change is written at once
+pub trait Storage: Send + Sync {
+    fn load_jobs(&self) -> Result<Vec<Job>, Error>;
+    fn load_users(&self) -> Result<Vec<User>, Error>;
+    fn load_contests(&self) -> Result<Vec<Contest>, Error>;
+    fn load_problems(&self) -> Result<Vec<Problem>, Error>;
+    fn load_rejudges(&self) -> Result<Vec<Rejudge>, Error>;
+    ///insert a job, or replace the one with the same id
+    fn save_job(&self, job: &Job) -> Result<(), Error>;
+    fn save_user(&self, user: &User) -> Result<(), Error>;
+    ///save a user, its password hash if given and the contests it was put in, all of them or none
+    fn save_user_with(&self, user: &User, password: Option<&str>, contests: &[Contest]) -> Result<(), Error>;
+    fn save_contest(&self, contest: &Contest) -> Result<(), Error>;
+    fn save_problem(&self, problem: &Problem) -> Result<(), Error>;
+    fn delete_problem(&self, id: i32) -> Result<(), Error>;
+    fn save_rejudge(&self, rejudge: &Rejudge) -> Result<(), Error>;
+    ///save a rejudge and the jobs it reset, all of them or none, so that no verdict before is lost
+    fn save_rejudge_with(&self, rejudge: &Rejudge, jobs: &[Job]) -> Result<(), Error>;
+    ///the page of jobs matching a filter of get_jobs, sorted as asked, and how many jobs match in all
+    fn query_jobs(&self, filter: &GetJob) -> Result<(Vec<Job>, u64), Error>;
+    ///store the password hash of a user
+    fn set_password(&self, user_id: i32, hash: &str) -> Result<(), Error>;
+    fn password_hash(&self, user_id: i32) -> Result<Option<String>, Error>;
+    ///remember a login token of a user until expires, in unix seconds
+    fn save_token(&self, token: &str, user_id: i32, expires: i64) -> Result<(), Error>;
+    ///the user a token unexpired at now belongs to
+    fn token_user(&self, token: &str, now: i64) -> Result<Option<i32>, Error>;
+    fn delete_token(&self, token: &str) -> Result<(), Error>;
+    ///forget everything
+    fn clear(&self) -> Result<(), Error>;
+}
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS jobs (
+    id INTEGER PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    contest_id INTEGER NOT NULL,
+    problem_id INTEGER NOT NULL,
+    language TEXT NOT NULL,
+    state TEXT NOT NULL,
+    result TEXT NOT NULL,
+    created_time TEXT NOT NULL,
+    data TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS jobs_user ON jobs (user_id);
+CREATE INDEX IF NOT EXISTS jobs_contest ON jobs (contest_id);
+CREATE INDEX IF NOT EXISTS jobs_problem ON jobs (problem_id);
+CREATE INDEX IF NOT EXISTS jobs_created ON jobs (created_time);
+CREATE TABLE IF NOT EXISTS users (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS users_name ON users (name);
+CREATE TABLE IF NOT EXISTS accounts (
+    user_id INTEGER PRIMARY KEY,
+    role TEXT NOT NULL,
+    password TEXT
+);
+CREATE TABLE IF NOT EXISTS tokens (
+    token TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    expires INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS contests (
+    id INTEGER PRIMARY KEY,
+    data TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS problems (
+    id INTEGER PRIMARY KEY,
+    data TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS rejudges (
+    id INTEGER PRIMARY KEY,
+    data TEXT NOT NULL
+);
+";
+
+///storage in an embedded sqlite database, a job is kept as json along with the columns it's filtered by
+pub struct SqliteStorage {
+    connection: Mutex<Connection>,
+}
+
+fn internal(err: impl std::fmt::Display) -> Error {
+    Error {
+        reason: Reason::ErrInternal,
+        code: 6,
+        message: format!("storage failed: {}", err),
+    }
+}
+
+///name of an enum variant as serialized, such as "Queueing"
+fn text<T: Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(s)) => s,
+        _ => String::new(),
+    }
+}
+
+fn load<T: DeserializeOwned>(connection: &Connection, sql: &str, args: &[&dyn ToSql]) -> Result<Vec<T>, Error> {
+    let mut statement = connection.prepare(sql).map_err(internal)?;
+    let rows = statement.query_map(args, |row| row.get::<_, String>(0)).map_err(internal)?;
+    let mut list = vec![];
+    for row in rows {
+        list.push(serde_json::from_str(&row.map_err(internal)?).map_err(internal)?);
+    }
+    Ok(list)
+}
+
+fn insert_job(connection: &Connection, job: &Job) -> Result<(), Error> {
+    connection
+        .execute(
+            "INSERT OR REPLACE INTO jobs (id, user_id, contest_id, problem_id, language, state, result, created_time, data)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                job.id,
+                job.submission.user_id,
+                job.submission.contest_id,
+                job.submission.problem_id,
+                job.submission.language,
+                text(&job.state),
+                text(&job.result),
+                job.created_time,
+                serde_json::to_string(job).map_err(internal)?,
+            ],
+        )
+        .map_err(internal)?;
+    Ok(())
+}
+
+fn insert_user(connection: &Connection, user: &User) -> Result<(), Error> {
+    connection
+        .execute("INSERT OR REPLACE INTO users (id, name) VALUES (?1, ?2)", params![user.id, user.name])
+        .map_err(internal)?;
+    connection
+        .execute(
+            "INSERT INTO accounts (user_id, role) VALUES (?1, ?2) ON CONFLICT (user_id) DO UPDATE SET role = excluded.role",
+            params![user.id, text(&user.role)],
+        )
+        .map_err(internal)?;
+    Ok(())
+}
+
+fn update_password(connection: &Connection, user_id: i32, hash: &str) -> Result<(), Error> {
+    connection
+        .execute("UPDATE accounts SET password = ?2 WHERE user_id = ?1", params![user_id, hash])
+        .map_err(internal)?;
+    Ok(())
+}
+
+fn insert_contest(connection: &Connection, contest: &Contest) -> Result<(), Error> {
+    connection
+        .execute(
+            "INSERT OR REPLACE INTO contests (id, data) VALUES (?1, ?2)",
+            params![contest.id, serde_json::to_string(contest).map_err(internal)?],
+        )
+        .map_err(internal)?;
+    Ok(())
+}
+
+impl SqliteStorage {
+    ///open or create a database file
+    pub fn open(path: &str) -> Result<SqliteStorage, Error> {
+        let connection = Connection::open(path).map_err(internal)?;
+        connection.execute_batch("PRAGMA journal_mode = WAL;").map_err(internal)?;
+        connection.execute_batch(SCHEMA).map_err(internal)?;
+        Ok(SqliteStorage { connection: Mutex::new(connection) })
+    }
+
+    ///Import jobs.json, users.json and contests.json written by older versions from dir into an empty database,
+    /// and rename them to *.imported so that it happens once. Return whether anything was imported.
+    pub fn import_json(&self, dir: &Path) -> Result<bool, Error> {
+        let files = ["jobs.json", "users.json", "contests.json"].map(|x| dir.join(x));
+        if !files.iter().all(|x| x.exists()) {
+            return Ok(false);
+        }
+        let mut connection = self.connection.lock().unwrap();
+        let used: Option<i32> = connection
+            .query_row("SELECT id FROM users LIMIT 1", [], |row| row.get(0))
+            .optional()
+            .map_err(internal)?;
+        if used.is_some() {
+            log::warn!("database is not empty, old json data in {} is not imported", dir.display());
+            return Ok(false);
+        }
+        let jobs: Vec<Job> = serde_json::from_str(&fs::read_to_string(&files[0]).map_err(internal)?).map_err(internal)?;
+        let users: Vec<User> = serde_json::from_str(&fs::read_to_string(&files[1]).map_err(internal)?).map_err(internal)?;
+        let contests: Vec<Contest> =
+            serde_json::from_str(&fs::read_to_string(&files[2]).map_err(internal)?).map_err(internal)?;
+        let transaction = connection.transaction().map_err(internal)?;
+        for job in &jobs {
+            insert_job(&transaction, job)?;
+        }
+        for user in &users {
+            insert_user(&transaction, user)?;
+        }
+        for contest in &contests {
+            insert_contest(&transaction, contest)?;
+        }
+        transaction.commit().map_err(internal)?;
+        for file in &files {
+            fs::rename(file, file.with_extension("json.imported")).map_err(internal)?;
+        }
+        log::info!("imported {} jobs, {} users and {} contests", jobs.len(), users.len(), contests.len());
+        Ok(true)
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn load_jobs(&self) -> Result<Vec<Job>, Error> {
+        load(&self.connection.lock().unwrap(), "SELECT data FROM jobs ORDER BY id", &[])
+    }
+
+    fn load_users(&self) -> Result<Vec<User>, Error> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare("SELECT id, name, role FROM users LEFT JOIN accounts ON id = user_id ORDER BY id")
+            .map_err(internal)?;
+        let rows = statement
+            .query_map([], |row| {
+                let role: Option<String> = row.get(2)?;
+                Ok(User {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    role: role.and_then(|x| serde_json::from_value(x.into()).ok()).unwrap_or_default(),
+                    password: None,
+                })
+            })
+            .map_err(internal)?;
+        rows.collect::<Result<_, _>>().map_err(internal)
+    }
+
+    fn load_contests(&self) -> Result<Vec<Contest>, Error> {
+        load(&self.connection.lock().unwrap(), "SELECT data FROM contests ORDER BY id", &[])
+    }
+
+    fn load_problems(&self) -> Result<Vec<Problem>, Error> {
+        load(&self.connection.lock().unwrap(), "SELECT data FROM problems ORDER BY id", &[])
+    }
+
+    fn load_rejudges(&self) -> Result<Vec<Rejudge>, Error> {
+        load(&self.connection.lock().unwrap(), "SELECT data FROM rejudges ORDER BY id", &[])
+    }
+
+    fn save_job(&self, job: &Job) -> Result<(), Error> {
+        insert_job(&self.connection.lock().unwrap(), job)
+    }
+
+    fn save_user(&self, user: &User) -> Result<(), Error> {
+        self.save_user_with(user, None, &[])
+    }
+
+    fn save_user_with(&self, user: &User, password: Option<&str>, contests: &[Contest]) -> Result<(), Error> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction().map_err(internal)?;
+        insert_user(&transaction, user)?;
+        if let Some(hash) = password {
+            update_password(&transaction, user.id.unwrap_or_default(), hash)?;
+        }
+        for contest in contests {
+            insert_contest(&transaction, contest)?;
+        }
+        transaction.commit().map_err(internal)
+    }
+
+    fn save_contest(&self, contest: &Contest) -> Result<(), Error> {
+        insert_contest(&self.connection.lock().unwrap(), contest)
+    }
+
+    fn save_problem(&self, problem: &Problem) -> Result<(), Error> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO problems (id, data) VALUES (?1, ?2)",
+                params![problem.id, serde_json::to_string(problem).map_err(internal)?],
+            )
+            .map_err(internal)?;
+        Ok(())
+    }
+
+    fn delete_problem(&self, id: i32) -> Result<(), Error> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM problems WHERE id = ?1", params![id])
+            .map_err(internal)?;
+        Ok(())
+    }
+
+    fn save_rejudge(&self, rejudge: &Rejudge) -> Result<(), Error> {
+        self.save_rejudge_with(rejudge, &[])
+    }
+
+    fn save_rejudge_with(&self, rejudge: &Rejudge, jobs: &[Job]) -> Result<(), Error> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction().map_err(internal)?;
+        transaction
+            .execute(
+                "INSERT OR REPLACE INTO rejudges (id, data) VALUES (?1, ?2)",
+                params![rejudge.id, serde_json::to_string(rejudge).map_err(internal)?],
+            )
+            .map_err(internal)?;
+        for job in jobs {
+            insert_job(&transaction, job)?;
+        }
+        transaction.commit().map_err(internal)
+    }
+
+    fn query_jobs(&self, filter: &GetJob) -> Result<(Vec<Job>, u64), Error> {
+        let range = filter.page_range()?;
+        let mut conditions: Vec<&str> = vec![];
+        let mut args: Vec<&dyn ToSql> = vec![];
+        let state = filter.state.as_ref().map(text);
+        let result = filter.result.as_ref().map(text);
+        if let Some(user_id) = &filter.user_id {
+            conditions.push("user_id = ?");
+            args.push(user_id);
+        }
+        if let Some(user_name) = &filter.user_name {
+            conditions.push("user_id IN (SELECT id FROM users WHERE name = ?)");
+            args.push(user_name);
+        }
+        if let Some(contest_id) = &filter.contest_id {
+            conditions.push("contest_id = ?");
+            args.push(contest_id);
+        }
+        if let Some(problem_id) = &filter.problem_id {
+            conditions.push("problem_id = ?");
+            args.push(problem_id);
+        }
+        if let Some(language) = &filter.language {
+            conditions.push("language = ?");
+            args.push(language);
+        }
+        //times are all in the same format, so they compare as text
+        if let Some(from) = &filter.from {
+            conditions.push("created_time > ?");
+            args.push(from);
+        }
+        if let Some(to) = &filter.to {
+            conditions.push("created_time < ?");
+            args.push(to);
+        }
+        if let Some(state) = &state {
+            conditions.push("state = ?");
+            args.push(state);
+        }
+        if let Some(result) = &result {
+            conditions.push("result = ?");
+            args.push(result);
+        }
+        let mut filtered = "FROM jobs".to_string();
+        if !conditions.is_empty() {
+            filtered += " WHERE ";
+            filtered += &conditions.join(" AND ");
+        }
+        let key = match filter.sort_by {
+            JobSortKey::Id => "id",
+            JobSortKey::CreatedTime => "created_time",
+            JobSortKey::Score => "json_extract(data, '$.score')",
+        };
+        let order = match filter.order {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        };
+        //id breaks ties, so that pages don't overlap
+        let mut sql = format!("SELECT data {} ORDER BY {} {}, id {}", filtered, key, order, order);
+        if let Some((offset, count)) = range {
+            sql += &format!(" LIMIT {} OFFSET {}", count, offset);
+        }
+        let connection = self.connection.lock().unwrap();
+        let total: u64 = connection
+            .query_row(&format!("SELECT COUNT(*) {}", filtered), args.as_slice(), |row| row.get(0))
+            .map_err(internal)?;
+        Ok((load(&connection, &sql, &args)?, total))
+    }
+
+    fn set_password(&self, user_id: i32, hash: &str) -> Result<(), Error> {
+        update_password(&self.connection.lock().unwrap(), user_id, hash)
+    }
+
+    fn password_hash(&self, user_id: i32) -> Result<Option<String>, Error> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row("SELECT password FROM accounts WHERE user_id = ?1", params![user_id], |row| row.get(0))
+            .optional()
+            .map(Option::flatten)
+            .map_err(internal)
+    }
+
+    fn save_token(&self, token: &str, user_id: i32, expires: i64) -> Result<(), Error> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute("INSERT INTO tokens (token, user_id, expires) VALUES (?1, ?2, ?3)", params![token, user_id, expires])
+            .map_err(internal)?;
+        Ok(())
+    }
+
+    fn token_user(&self, token: &str, now: i64) -> Result<Option<i32>, Error> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT user_id FROM tokens WHERE token = ?1 AND expires > ?2",
+                params![token, now],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(internal)
+    }
+
+    fn delete_token(&self, token: &str) -> Result<(), Error> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM tokens WHERE token = ?1 OR expires <= strftime('%s', 'now')", params![token])
+            .map_err(internal)?;
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<(), Error> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute_batch(
+                concat!(
+                    "DELETE FROM jobs; DELETE FROM users; DELETE FROM contests; DELETE FROM accounts; DELETE FROM tokens;",
+                    "DELETE FROM problems; DELETE FROM rejudges;"
+                ),
+            )
+            .map_err(internal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Role;
+    use crate::PostJob;
+
+    fn job(id: i32, user_id: i32, language: &str) -> Job {
+        Job::new(
+            id,
+            &PostJob {
+                source_code: String::new(),
+                language: language.to_string(),
+                user_id,
+                contest_id: 0,
+                problem_id: 0,
+            },
+        )
+    }
+
+    fn user(id: i32, name: &str) -> User {
+        User { id: Some(id), name: name.to_string(), role: Role::Contestant, password: None }
+    }
+
+    #[test]
+    fn query_filters_like_match_job() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        storage.save_user(&user(0, "root")).unwrap();
+        storage.save_user(&user(1, "alice")).unwrap();
+        storage.save_job(&job(0, 0, "Rust")).unwrap();
+        storage.save_job(&job(1, 1, "Rust")).unwrap();
+        storage.save_job(&job(2, 1, "Python")).unwrap();
+        let filter: GetJob = serde_json::from_str(r#"{"user_name": "alice", "language": "Rust", "state": "Queueing"}"#).unwrap();
+        let (jobs, total) = storage.query_jobs(&filter).unwrap();
+        assert_eq!(jobs.iter().map(|x| x.id).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(total, 1);
+        let all: GetJob = serde_json::from_str("{}").unwrap();
+        assert_eq!(storage.query_jobs(&all).unwrap().0.len(), 3);
+    }
+
+    #[test]
+    fn query_pages_sorted_jobs() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        for id in 0..5 {
+            let mut saved = job(id, 0, "Rust");
+            saved.score = (id % 3) as f64;
+            storage.save_job(&saved).unwrap();
+        }
+        let filter: GetJob = serde_json::from_str(r#"{"page": 2, "per_page": 2, "sort_by": "score", "order": "desc"}"#).unwrap();
+        let (jobs, total) = storage.query_jobs(&filter).unwrap();
+        //scores 0, 1, 2, 0, 1 sorted down are jobs 2, 4, 1, 3, 0
+        assert_eq!(jobs.iter().map(|x| x.id).collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(total, 5);
+        let filter: GetJob = serde_json::from_str(r#"{"page": 0}"#).unwrap();
+        assert!(storage.query_jobs(&filter).is_err());
+    }
+
+    #[test]
+    fn saving_replaces_job() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        let mut saved = job(0, 0, "Rust");
+        storage.save_job(&saved).unwrap();
+        saved.cancel();
+        storage.save_job(&saved).unwrap();
+        let jobs = storage.load_jobs().unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert!(jobs[0].is_canceled());
+    }
+
+    #[test]
+    fn user_is_saved_with_password_and_contests() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        let contest: Contest = serde_json::from_value(serde_json::json!({
+            "id": 0, "name": "", "from": "", "to": "", "problem_ids": [], "user_ids": [1], "submission_limit": 0
+        }))
+        .unwrap();
+        storage.save_user_with(&user(1, "alice"), Some("hash"), &[contest]).unwrap();
+        assert_eq!(storage.load_users().unwrap()[0].name, "alice");
+        assert_eq!(storage.password_hash(1).unwrap().as_deref(), Some("hash"));
+        assert_eq!(storage.load_contests().unwrap()[0].user_ids, vec![1]);
+    }
+
+    #[test]
+    fn rejudge_is_saved_with_jobs() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        let mut rejudged = job(0, 0, "Rust");
+        rejudged.cancel();
+        storage.save_job(&rejudged).unwrap();
+        let mut rejudge = Rejudge::new(0, serde_json::from_str("{}").unwrap());
+        rejudge.add(&rejudged);
+        rejudged.reset(1);
+        storage.save_rejudge_with(&rejudge, &[rejudged]).unwrap();
+        assert_eq!(storage.load_rejudges().unwrap().len(), 1);
+        assert!(storage.load_jobs().unwrap()[0].is_pending());
+    }
+
+    #[test]
+    fn token_expires() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        let mut admin = user(0, "root");
+        admin.role = Role::Admin;
+        storage.save_user(&admin).unwrap();
+        storage.set_password(0, "hash").unwrap();
+        assert_eq!(storage.password_hash(0).unwrap().as_deref(), Some("hash"));
+        assert!(matches!(storage.load_users().unwrap()[0].role, Role::Admin));
+        storage.save_token("token", 0, 100).unwrap();
+        assert_eq!(storage.token_user("token", 99).unwrap(), Some(0));
+        assert_eq!(storage.token_user("token", 100).unwrap(), None);
+    }
+}
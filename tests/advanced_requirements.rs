@@ -126,3 +126,162 @@ fn test_adv_07_10_pts_dynamic_ranking() {
         );
     }
 }
+
+#[test]
+fn test_adv_08_job_cancellation() {
+    // cancel a queueing job and a running one with a single judge worker
+    // check:
+    // 1. both end canceled at once, and can't be canceled again
+    // 2. canceling a missing job is not found
+    TestCase::read("adv_08_job_cancellation").run();
+}
+
+#[test]
+fn test_adv_09_problem_management() {
+    // create, judge against, update, and delete a problem at run time
+    // check:
+    // 1. a problem without cases is refused
+    // 2. a posted problem is judged and kept across restarts
+    // 3. a deleted problem is gone
+    TestCase::read("adv_09_problem_management").run();
+}
+
+#[test]
+fn test_adv_10_problem_package() {
+    // load a problem from a package directory given in config
+    // check:
+    // 1. cases are found in data/ in order, with the score shared evenly
+    // 2. the statement is read from statement.md
+    // 3. a correct submission is accepted
+    TestCase::read("adv_10_problem_package").run();
+}
+
+#[test]
+fn test_adv_11_testlib_checker() {
+    // judge with a checker built from source in a problem package
+    // check:
+    // 1. the checker gets input, output and answer files, and exit code 0 is accepted
+    // 2. exit code 1 is wrong answer, with the checker's message as info
+    TestCase::read("adv_11_testlib_checker").run();
+}
+
+#[test]
+fn test_adv_12_partial_scoring() {
+    // judge with a checker giving part of the score of cases
+    // check:
+    // 1. each case carries the score it earned
+    // 2. a pack earns the lowest ratio of its cases
+    TestCase::read("adv_12_partial_scoring").run();
+}
+
+#[test]
+fn test_adv_13_interactive_problem() {
+    // judge a guessing game with an interactor talking to the program
+    // check:
+    // 1. a binary search is accepted, with the interactor's message as info
+    // 2. a linear search runs out of tries and is wrong answer
+    TestCase::read("adv_13_interactive_problem").run();
+}
+
+#[test]
+fn test_adv_14_float_comparison() {
+    // compare numbers of a float problem within its epsilon
+    // check:
+    // 1. an answer with 7 digits after the point is accepted
+    // 2. an answer with 3 digits after the point is wrong answer
+    TestCase::read("adv_14_float_comparison").run();
+}
+
+#[test]
+fn test_adv_15_unordered_lines() {
+    // compare output lines in any order
+    // check:
+    // 1. the lines of the answer reversed are accepted
+    // 2. a missing line is wrong answer
+    TestCase::read("adv_15_unordered_lines").run();
+}
+
+#[test]
+fn test_adv_16_presentation_error() {
+    // tell strict output differing from the answer only in whitespace
+    // check:
+    // 1. output equal to the answer is accepted
+    // 2. output with a trailing space is presentation error
+    // 3. jobs are filtered by presentation error
+    TestCase::read("adv_16_presentation_error").run();
+}
+
+#[test]
+fn test_adv_17_output_limit() {
+    // limit output of a case and read it while the program runs
+    // check:
+    // 1. output within the limit is accepted
+    // 2. printing forever is output limit exceeded, not time limit exceeded
+    // 3. output not in UTF-8 is wrong answer
+    TestCase::read("adv_17_output_limit").run();
+}
+
+#[test]
+fn test_adv_18_runtime_error_info() {
+    // tell why a program failed
+    // check:
+    // 1. an exit code is told with the end of stderr
+    // 2. a signal is told by name
+    // 3. correct output with a non-zero exit code is accepted where exit codes are ignored
+    // 4. and is a runtime error elsewhere
+    TestCase::read("adv_18_runtime_error_info").run();
+}
+
+#[test]
+fn test_adv_19_cpu_time_limit() {
+    // limit cpu time of a case, and time on the clock more generously
+    // check:
+    // 1. sleeping longer than the time limit is accepted
+    // 2. running forever is time limit exceeded
+    // 3. sleeping forever is time limit exceeded as well
+    TestCase::read("adv_19_cpu_time_limit").run();
+}
+
+#[test]
+fn test_adv_20_bulk_rejudge() {
+    // rejudge all jobs of a problem after its answer is fixed
+    // check:
+    // 1. every job passing the filter is rejudged, with its verdict before kept
+    // 2. the rejudge tells how many jobs finished and how many changed
+    // 3. an unknown rejudge is not found
+    TestCase::read("adv_20_bulk_rejudge").run();
+}
+
+#[test]
+fn test_adv_21_job_events() {
+    // follow a job through server-sent events
+    // check:
+    // 1. the event stream of a job ends with its finished version
+    // 2. a finished job streams once and ends
+    // 3. events of an unknown job are not found
+    // 4. a feed filtered by a malformed time is a bad request
+    TestCase::read("adv_21_job_events").run();
+}
+
+#[test]
+fn test_adv_22_login() {
+    // log in with auth enabled
+    // check:
+    // 1. a wrong password and an unknown user are unauthorized alike
+    // 2. a write route without a token is unauthorized
+    // 3. a contestant on an admin route is forbidden, an admin is not
+    // 4. a token is no longer valid after logout
+    TestCase::read("adv_22_login").run();
+}
+
+#[test]
+fn test_adv_23_problem_setter() {
+    // keep problem setters from running commands or reading files on the server
+    // check:
+    // 1. a problem setter can't give a special judge, on a new problem or an old one
+    // 2. nor test data outside the problem's own directory
+    // 3. but can post a problem with test data in it
+    // 4. an admin can do both
+    // 5. the id of a deleted problem with jobs is not given again
+    TestCase::read("adv_23_problem_setter").run();
+}
@@ -4,6 +4,7 @@ use reqwest::blocking::{Client, RequestBuilder};
 use serde::{Deserialize, Serialize};
 use serde_json;
 use serde_json::Value;
+use std::collections::HashMap;
 use std::env::consts::EXE_EXTENSION;
 use std::fs::{File, OpenOptions};
 use std::io::Write;
@@ -106,6 +107,8 @@ struct HttpComm {
     poll_count: u64,
     #[serde(default = "_default_false")]
     restart_server: bool, // restart server before sending request
+    #[serde(default)]
+    as_user: Option<String>, // send the token of the last login of this user
 }
 
 pub struct TestCase {
@@ -117,6 +120,7 @@ pub struct TestCase {
     stdout_file: PathBuf,
     stderr_file: PathBuf,
     http_file: PathBuf,
+    tokens: HashMap<String, String>, // login token of each user name
 }
 
 impl TestCase {
@@ -149,6 +153,7 @@ impl TestCase {
             stdout_file,
             stderr_file,
             http_file,
+            tokens: HashMap::new(),
         }
     }
 
@@ -287,10 +292,14 @@ impl TestCase {
         let method =
             reqwest::Method::from_bytes(&c.request.method.to_uppercase().as_bytes()).unwrap();
 
+        let token = c.as_user.as_ref().and_then(|x| self.tokens.get(x)).cloned();
         let check_status_and_get_body = |url: &str, method: reqwest::Method| -> Value {
             let mut request = CLIENT
                 .request(method.clone(), url)
                 .timeout(Duration::from_millis(c.timeout));
+            if let Some(token) = &token {
+                request = request.bearer_auth(token);
+            }
             if let reqwest::Method::GET = method {
                 // no json body
             } else {
@@ -306,13 +315,30 @@ impl TestCase {
                 "case {} incorrect: wrong status code",
                 self.name
             );
-            let json: Value = resp.json().expect(
-                format!(
-                    "case {} incorrect: cannot decode response body as JSON, status code is {}",
-                    self.name, code
+            let is_event_stream = resp
+                .headers()
+                .get("content-type")
+                .is_some_and(|x| x.as_bytes().starts_with(b"text/event-stream"));
+            let json: Value = if is_event_stream {
+                // an event stream is read until it ends, and compared by its last event
+                let text = resp
+                    .text()
+                    .unwrap_or_else(|_| panic!("case {} incorrect: event stream did not end", self.name));
+                let last = text.lines().filter_map(|x| x.strip_prefix("data: ")).next_back();
+                serde_json::from_str(last.unwrap_or("null")).unwrap_or_else(|_| {
+                    panic!("case {} incorrect: cannot decode last event as JSON", self.name)
+                })
+            } else {
+                // an empty body, such as of a logout, is taken as null
+                let text = resp.text().unwrap_or_default();
+                serde_json::from_str(if text.is_empty() { "null" } else { &text }).expect(
+                    format!(
+                        "case {} incorrect: cannot decode response body as JSON, status code is {}",
+                        self.name, code
+                    )
+                    .as_str(),
                 )
-                .as_str(),
-            );
+            };
 
             serde_json::to_writer(&http_file, &json).ok();
             writeln!(http_file).ok();
@@ -354,6 +380,11 @@ impl TestCase {
             }
         }
 
+        // remember the token of a login
+        if let (Some(token), Some(name)) = (body["token"].as_str(), body["user"]["name"].as_str()) {
+            self.tokens.insert(name.to_string(), token.to_string());
+        }
+
         // check final result
         if let Err(error) = assert_json_matches_no_panic(
             &body,
@@ -381,3 +412,10 @@ impl TestCase {
         res
     }
 }
+
+impl Drop for TestCase {
+    // a failed case stops its server too, which with auth enabled the next case couldn't stop
+    fn drop(&mut self) {
+        self.kill_server();
+    }
+}
@@ -0,0 +1,19 @@
+#!/usr/bin/env python3
+# guess a number in [1, 10000] within 20 tries, told "<" or ">" after each wrong one; testlib exit codes
+import sys
+
+secret = int(open(sys.argv[1]).read())
+for tries in range(20):
+    line = sys.stdin.readline()
+    try:
+        guess = int(line)
+    except ValueError:
+        print('wrong answer expected a number, found %r' % line.strip(), file=sys.stderr)
+        sys.exit(2)
+    if guess == secret:
+        print('=', flush=True)
+        print('ok found in %d tries' % (tries + 1), file=sys.stderr)
+        sys.exit(0)
+    print('<' if guess < secret else '>', flush=True)
+print('wrong answer not found in 20 tries', file=sys.stderr)
+sys.exit(1)
@@ -0,0 +1,21 @@
+// gives half of the score to a + b off by at most 10, with the testlib exit code of 50 percent
+use std::{env, fs, process};
+
+fn number(path: &str) -> i64 {
+    let text = fs::read_to_string(path).unwrap_or_else(|_| process::exit(3));
+    text.trim().parse().unwrap_or_else(|_| process::exit(2))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let (found, expected) = (number(&args[2]), number(&args[3]));
+    if found == expected {
+        process::exit(0);
+    }
+    if (found - expected).abs() <= 10 {
+        eprintln!("close, expected {}, found {}", expected, found);
+        process::exit(16 + 50);
+    }
+    eprintln!("wrong answer expected {}, found {}", expected, found);
+    process::exit(1);
+}